@@ -1,6 +1,6 @@
 use std::sync::Mutex;
 
-use conhash::{ConsistentHash, Node};
+use conhash::{hash::Md5U64, ConsistentHash, FrozenConsistentHash, Node, SharedConsistentHash};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use once_cell::sync::Lazy;
 
@@ -17,6 +17,7 @@ impl Node for ServerNode {
 }
 
 impl ServerNode {
+    #[allow(clippy::redundant_field_names)]
     fn new(host: &str, port: u16) -> ServerNode {
         ServerNode {
             host: host.to_owned(),
@@ -26,19 +27,36 @@ impl ServerNode {
 }
 
 type Nodes = ConsistentHash<ServerNode>;
+type Nodes64 = ConsistentHash<ServerNode, Md5U64>;
 
 static CH: Lazy<Nodes> = Lazy::new(|| new_large_nodes());
 static CH_MUT: Lazy<Mutex<Nodes>> = Lazy::new(|| Mutex::new(new_large_nodes()));
+static CH_FROZEN: Lazy<FrozenConsistentHash<ServerNode, Md5U64>> = Lazy::new(|| {
+    let mut ch = Nodes64::with_hasher(Md5U64);
+    add_large_nodes(|node, replicas| ch.add(node, replicas));
+    ch.freeze()
+});
+static CH_SHARED: Lazy<SharedConsistentHash<ServerNode>> =
+    Lazy::new(|| SharedConsistentHash::new(new_large_nodes()));
+static CH_U64: Lazy<Nodes64> = Lazy::new(|| {
+    let mut ch = Nodes64::with_hasher(Md5U64);
+    add_large_nodes(|node, replicas| ch.add(node, replicas));
+    ch
+});
 
-fn new_large_nodes() -> Nodes {
+fn add_large_nodes<F: FnMut(&ServerNode, usize)>(mut add: F) {
     const NODES: usize = 1000;
     const REPLICAS: usize = NODES;
-    let mut ch = Nodes::new();
 
     for i in 0..NODES {
         let node = ServerNode::new("localhost", 10000 + i as u16);
-        ch.add(&node, REPLICAS);
+        add(&node, REPLICAS);
     }
+}
+
+fn new_large_nodes() -> Nodes {
+    let mut ch = Nodes::new();
+    add_large_nodes(|node, replicas| ch.add(node, replicas));
     ch
 }
 
@@ -46,17 +64,48 @@ fn get(key: &str) {
     CH.get_str(key);
 }
 
+fn get_u64(key: &str) {
+    CH_U64.get_str(key);
+}
+
 fn get_mut(key: &str) {
     CH_MUT.lock().unwrap().get_str_mut(key);
 }
 
+fn get_frozen(key: &str) {
+    CH_FROZEN.get_str(key);
+}
+
+fn get_shared(key: &str) {
+    CH_SHARED.get_str(key);
+}
+
 fn bench_get(c: &mut Criterion) {
     c.bench_function("get", |b| b.iter(|| get(black_box(""))));
 }
 
+fn bench_get_u64(c: &mut Criterion) {
+    c.bench_function("get_u64", |b| b.iter(|| get_u64(black_box(""))));
+}
+
 fn bench_get_mut(c: &mut Criterion) {
     c.bench_function("get_mut", |b| b.iter(|| get_mut(black_box(""))));
 }
 
-criterion_group!(benches, bench_get, bench_get_mut);
+fn bench_get_frozen(c: &mut Criterion) {
+    c.bench_function("get_frozen", |b| b.iter(|| get_frozen(black_box(""))));
+}
+
+fn bench_get_shared(c: &mut Criterion) {
+    c.bench_function("get_shared", |b| b.iter(|| get_shared(black_box(""))));
+}
+
+criterion_group!(
+    benches,
+    bench_get,
+    bench_get_u64,
+    bench_get_mut,
+    bench_get_shared,
+    bench_get_frozen
+);
 criterion_main!(benches);
@@ -0,0 +1,302 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [AnchorHash](https://arxiv.org/abs/1812.09674)
+//!
+//! Nodes occupy buckets out of a fixed capacity (the anchor). Any node can be removed, and
+//! only the keys of the removed node are remapped. Lookups take O(1) expected time and no
+//! memory is used per virtual node. A node added after removals takes the bucket of the
+//! most recently removed node.
+
+use std::collections::HashMap;
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+/// AnchorHash
+pub struct AnchorHash<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    // Size of the working set when the bucket was removed, 0 for working buckets
+    anchor: Vec<usize>,
+    // Bucket that replaced the bucket when it was removed
+    next: Vec<usize>,
+    // Working buckets, the first `size` entries are in use
+    working: Vec<usize>,
+    // Location of each bucket in `working`
+    location: Vec<usize>,
+    // Removed buckets, the most recently removed on top
+    removed: Vec<usize>,
+    size: usize,
+    nodes: Vec<Option<N>>,
+    buckets: HashMap<String, usize>,
+}
+
+impl<N: Node> AnchorHash<N> {
+    /// Construct with default hash function (Md5), holding at most `capacity` nodes
+    #[cfg(feature = "md5")]
+    pub fn new(capacity: usize) -> AnchorHash<N> {
+        AnchorHash::with_hash(default_md5_hash_fn, capacity)
+    }
+
+    /// Construct with customized hash function, holding at most `capacity` nodes
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>, capacity: usize) -> AnchorHash<N> {
+        AnchorHash::with_hasher(DigestU64(hash_fn), capacity)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> AnchorHash<N, H> {
+    /// Construct with customized `RingHasher`, holding at most `capacity` nodes
+    pub fn with_hasher(hasher: H, capacity: usize) -> AnchorHash<N, H> {
+        assert!(capacity > 0, "capacity must be at least 1");
+
+        // Start with every bucket removed, as if they were removed from the last to the first
+        AnchorHash {
+            hasher,
+            anchor: (0..capacity).collect(),
+            next: (0..capacity).collect(),
+            working: (0..capacity).collect(),
+            location: (0..capacity).collect(),
+            removed: (0..capacity).rev().collect(),
+            size: 0,
+            nodes: vec![None; capacity],
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Add a new node into a free bucket. A node with the same name is replaced in its bucket
+    ///
+    /// Return `false` if all buckets are in use.
+    pub fn add(&mut self, node: &N) -> bool {
+        let node_name = node.name();
+        if let Some(&bucket) = self.buckets.get(&node_name) {
+            debug!("Replacing node {:?} in bucket {}", node_name, bucket);
+            self.nodes[bucket] = Some(node.clone());
+            return true;
+        }
+
+        let bucket = match self.removed.pop() {
+            Some(bucket) => bucket,
+            None => {
+                debug!(
+                    "No free bucket for node {:?}, capacity is {}",
+                    node_name,
+                    self.capacity()
+                );
+                return false;
+            }
+        };
+        debug!("Adding node {:?} to bucket {}", node_name, bucket);
+
+        self.anchor[bucket] = 0;
+        self.location[self.working[self.size]] = self.size;
+        self.working[self.location[bucket]] = bucket;
+        self.next[bucket] = bucket;
+        self.size += 1;
+
+        self.nodes[bucket] = Some(node.clone());
+        self.buckets.insert(node_name, bucket);
+        true
+    }
+
+    /// Remove a node, freeing its bucket
+    pub fn remove(&mut self, node: &N) {
+        let node_name = node.name();
+        let bucket = match self.buckets.remove(&node_name) {
+            Some(bucket) => bucket,
+            None => {
+                debug!("Node {:?} not exists", node_name);
+                return;
+            }
+        };
+        debug!("Removing node {:?} from bucket {}", node_name, bucket);
+
+        self.removed.push(bucket);
+        self.size -= 1;
+        self.anchor[bucket] = self.size;
+        let last = self.working[self.size];
+        self.working[self.location[bucket]] = last;
+        self.next[bucket] = last;
+        self.location[last] = self.location[bucket];
+
+        self.nodes[bucket] = None;
+    }
+
+    /// Get the bucket number by key. Return `None` if no valid node inside
+    pub fn bucket(&self, key: &[u8]) -> Option<usize> {
+        if self.size == 0 {
+            debug!("The container is empty");
+            return None;
+        }
+
+        let b = self.bucket_of(self.hasher.hash(key));
+        debug!("Getting key {:?}, bucket is {}", key, b);
+        Some(b)
+    }
+
+    // Working bucket of a hashed key
+    fn bucket_of(&self, hashed_key: u64) -> usize {
+        let mut b = (hashed_key % self.capacity() as u64) as usize;
+        while self.anchor[b] > 0 {
+            // Rehash into the working set at the time `b` was removed
+            let seeded = mix64(hashed_key ^ mix64(b as u64 + 1));
+            let mut h = (seeded % self.anchor[b] as u64) as usize;
+            while self.anchor[h] >= self.anchor[b] {
+                h = self.next[h];
+            }
+            b = h;
+        }
+        b
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.bucket(key).and_then(|b| self.nodes[b].as_ref())
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key, the key's node first
+    ///
+    /// The other buckets are found by the key re-hashed with the replica number, skipping
+    /// buckets already taken, so the replicas of a key are spread like the keys themselves.
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        if self.size == 0 {
+            debug!("The container is empty");
+            return Vec::new();
+        }
+
+        let hashed_key = self.hasher.hash(key);
+        let want = n.min(self.size);
+        let mut taken = Vec::with_capacity(want);
+        let mut replica = 0;
+        while taken.len() < want {
+            let seeded = if replica == 0 {
+                hashed_key
+            } else {
+                mix64(hashed_key ^ mix64(replica))
+            };
+            let bucket = self.bucket_of(seeded);
+            if !taken.contains(&bucket) {
+                taken.push(bucket);
+            }
+            replica += 1;
+        }
+        taken
+            .into_iter()
+            .filter_map(|bucket| self.nodes[bucket].as_ref())
+            .collect()
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Maximum number of nodes
+    pub fn capacity(&self) -> usize {
+        self.anchor.len()
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    fn owners(ch: &AnchorHash<ServerNode>) -> Vec<ServerNode> {
+        (0..2000)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn capacity_is_bounded() {
+        let mut ch = AnchorHash::with_hash(test_hash, 3);
+        for node in local_nodes(3).iter() {
+            assert!(ch.add(node));
+        }
+        assert!(!ch.add(&ServerNode::new("localhost", 12348)));
+        assert!(ch.add(&ServerNode::new("localhost", 12345)));
+        assert_eq!(ch.len(), 3);
+    }
+
+    #[test]
+    fn arbitrary_removals() {
+        let nodes = local_nodes(20);
+
+        let mut ch = AnchorHash::with_hash(test_hash, 64);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+        assert_eq!(ch.len(), nodes.len());
+
+        let mut states = vec![owners(&ch)];
+        for idx in [7, 0, 13, 19, 2] {
+            ch.remove(&nodes[idx]);
+            let before = states.last().unwrap();
+            let after = owners(&ch);
+            for (old, new) in before.iter().zip(after.iter()) {
+                assert_ne!(new, &nodes[idx]);
+                if old != &nodes[idx] {
+                    assert_eq!(old, new);
+                }
+            }
+            states.push(after);
+        }
+        assert_eq!(ch.len(), nodes.len() - 5);
+
+        // Adding nodes back restores the earlier states
+        states.pop();
+        for idx in [2, 19, 13, 0, 7] {
+            ch.add(&nodes[idx]);
+            assert_eq!(&owners(&ch), states.last().unwrap());
+            states.pop();
+        }
+    }
+
+    #[test]
+    fn balanced_load() {
+        let mut ch = AnchorHash::with_hash(test_hash, 100);
+        for node in local_nodes(10).iter() {
+            ch.add(node);
+        }
+
+        const KEYS: usize = 20000;
+        let mut loads = HashMap::new();
+        for i in 0..KEYS {
+            let node = ch.get_str(&format!("{}", i)).unwrap();
+            *loads.entry(node.name()).or_insert(0usize) += 1;
+        }
+
+        let peak = *loads.values().max().unwrap() as f64;
+        let average = KEYS as f64 / 10.0;
+        assert!(peak / average < 1.1, "peak-to-average {}", peak / average);
+
+        let list = ch.get_str_n("hello", 20);
+        assert_eq!(list.len(), 10);
+        assert_eq!(Some(list[0]), ch.get_str("hello"));
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Consistent Hashing with Bounded Loads](https://arxiv.org/abs/1608.01350)
+//!
+//! Keys are looked up on the ring as usual, but nodes whose load has reached
+//! `ceil((1 + epsilon) * average)` are skipped, so no node receives much more than its
+//! fair share while keys still stick to the same node when possible.
+
+use std::collections::HashMap;
+
+use crate::{hash::RingHasher, ConsistentHash, Node};
+
+/// Consistent Hash with bounded loads
+pub struct BoundedLoadConsistentHash<N: Node, H: RingHasher = fn(&[u8]) -> Vec<u8>> {
+    ring: ConsistentHash<N, H>,
+    epsilon: f64,
+    loads: HashMap<String, usize>,
+    total_load: usize,
+}
+
+impl<N: Node> BoundedLoadConsistentHash<N> {
+    /// Construct with default hash function (Md5) and the load balancing parameter `epsilon`
+    #[cfg(feature = "md5")]
+    pub fn new(epsilon: f64) -> BoundedLoadConsistentHash<N> {
+        BoundedLoadConsistentHash::with_ring(ConsistentHash::new(), epsilon)
+    }
+
+    /// Construct with customized hash function and the load balancing parameter `epsilon`
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>, epsilon: f64) -> BoundedLoadConsistentHash<N> {
+        BoundedLoadConsistentHash::with_ring(ConsistentHash::with_hash(hash_fn), epsilon)
+    }
+}
+
+impl<N: Node, H: RingHasher> BoundedLoadConsistentHash<N, H> {
+    /// Construct with customized `RingHasher` and the load balancing parameter `epsilon`
+    pub fn with_hasher(hasher: H, epsilon: f64) -> BoundedLoadConsistentHash<N, H> {
+        BoundedLoadConsistentHash::with_ring(ConsistentHash::with_hasher(hasher), epsilon)
+    }
+
+    fn with_ring(ring: ConsistentHash<N, H>, epsilon: f64) -> BoundedLoadConsistentHash<N, H> {
+        assert!(
+            epsilon.is_finite() && epsilon >= 0.0,
+            "epsilon must be a non-negative number, but got {}",
+            epsilon
+        );
+
+        BoundedLoadConsistentHash {
+            ring,
+            epsilon,
+            loads: HashMap::new(),
+            total_load: 0,
+        }
+    }
+
+    /// Add a new node. The load of an existing node is kept
+    ///
+    /// A node needs at least one replica, as every node counts towards the average load.
+    pub fn add(&mut self, node: &N, num_replicas: usize) {
+        assert!(num_replicas > 0, "num_replicas must be at least 1");
+        self.ring.add(node, num_replicas);
+        self.loads.entry(node.name()).or_insert(0);
+    }
+
+    /// Remove a node with all replicas (virtual nodes) and forget its load
+    pub fn remove(&mut self, node: &N) {
+        self.ring.remove(node);
+        if let Some(load) = self.loads.remove(&node.name()) {
+            self.total_load -= load;
+        }
+    }
+
+    /// Report the current load of a node
+    pub fn set_load(&mut self, node: &N, load: usize) {
+        match self.loads.get_mut(&node.name()) {
+            Some(current) => {
+                self.total_load = self.total_load - *current + load;
+                *current = load;
+            }
+            None => debug!("Node {:?} not exists", node.name()),
+        }
+    }
+
+    /// Current load of a node
+    pub fn load(&self, node: &N) -> usize {
+        self.loads.get(&node.name()).copied().unwrap_or(0)
+    }
+
+    /// Sum of the loads of all nodes
+    pub fn total_load(&self) -> usize {
+        self.total_load
+    }
+
+    /// Maximum load a node may have when it is chosen for one more key
+    pub fn capacity(&self) -> usize {
+        if self.loads.is_empty() {
+            return 0;
+        }
+
+        let average = (self.total_load + 1) as f64 / self.loads.len() as f64;
+        ((1.0 + self.epsilon) * average).ceil() as usize
+    }
+
+    /// Get a node by key, skipping nodes that are already at capacity.
+    /// Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        let capacity = self.capacity();
+        let loads = &self.loads;
+        self.ring.get_with(key, |node| {
+            loads.get(&node.name()).copied().unwrap_or(0) < capacity
+        })
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get a node by key and increase its load by one
+    ///
+    /// Call `release` with the returned node when the work assigned to it is done.
+    pub fn acquire<'a>(&'a mut self, key: &[u8]) -> Option<&'a N> {
+        let capacity = self.capacity();
+        let loads = &self.loads;
+        let node = self.ring.get_with(key, |node| {
+            loads.get(&node.name()).copied().unwrap_or(0) < capacity
+        })?;
+
+        if let Some(load) = self.loads.get_mut(&node.name()) {
+            *load += 1;
+            self.total_load += 1;
+        }
+        Some(node)
+    }
+
+    /// Get a node by string key and increase its load by one
+    pub fn acquire_str<'a>(&'a mut self, key: &str) -> Option<&'a N> {
+        self.acquire(key.as_bytes())
+    }
+
+    /// Decrease the load of a node by one
+    pub fn release(&mut self, node: &N) {
+        match self.loads.get_mut(&node.name()) {
+            Some(load) if *load > 0 => {
+                *load -= 1;
+                self.total_load -= 1;
+            }
+            Some(_) => debug!("Node {:?} has no load to release", node.name()),
+            None => debug!("Node {:?} not exists", node.name()),
+        }
+    }
+
+    /// The underlying ring
+    pub fn ring(&self) -> &ConsistentHash<N, H> {
+        &self.ring
+    }
+
+    /// Number of nodes (virtual nodes included)
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    #[test]
+    #[should_panic]
+    fn node_without_replicas() {
+        let mut ch = BoundedLoadConsistentHash::with_hash(test_hash, 0.25);
+        ch.add(&ServerNode::new("localhost", 12345), 0);
+    }
+
+    #[test]
+    fn unloaded_matches_ring() {
+        let mut ch = BoundedLoadConsistentHash::with_hash(test_hash, 0.25);
+        for node in local_nodes(5).iter() {
+            ch.add(node, 20);
+        }
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), ch.ring().get_str(&s));
+        }
+    }
+
+    #[test]
+    fn loads_are_bounded() {
+        let nodes = local_nodes(5);
+
+        let mut ch = BoundedLoadConsistentHash::with_hash(test_hash, 0.25);
+        assert_eq!(ch.capacity(), 0);
+        assert_eq!(ch.acquire_str("hot"), None);
+        for node in nodes.iter() {
+            ch.add(node, 20);
+        }
+
+        // Every key is the same hot key
+        for _ in 0..1000 {
+            let capacity = ch.capacity();
+            let node = ch.acquire_str("hot").unwrap().clone();
+            assert!(ch.load(&node) <= capacity);
+        }
+        assert_eq!(ch.total_load(), 1000);
+
+        let max_load = nodes.iter().map(|n| ch.load(n)).max().unwrap();
+        assert!(max_load <= (1.25 * 1000.0 / nodes.len() as f64).ceil() as usize);
+
+        for node in nodes.iter() {
+            while ch.load(node) > 0 {
+                ch.release(node);
+            }
+        }
+        assert_eq!(ch.total_load(), 0);
+        assert_eq!(ch.get_str("hot"), ch.ring().get_str("hot"));
+    }
+
+    #[test]
+    fn reported_loads() {
+        let node0 = ServerNode::new("localhost", 12345);
+        let node1 = ServerNode::new("localhost", 12346);
+
+        let mut ch = BoundedLoadConsistentHash::with_hash(test_hash, 0.0);
+        ch.add(&node0, 20);
+        ch.add(&node1, 20);
+
+        let owner = ch.get_str("hello").unwrap().clone();
+        let other = if owner == node0 {
+            node1.clone()
+        } else {
+            node0.clone()
+        };
+
+        ch.set_load(&owner, 10);
+        assert_eq!(ch.total_load(), 10);
+        assert_eq!(ch.get_str("hello"), Some(&other));
+
+        ch.set_load(&other, 10);
+        assert_eq!(ch.get_str("hello"), Some(&owner));
+
+        ch.remove(&other);
+        assert_eq!(ch.total_load(), 10);
+        assert_eq!(ch.get_str("hello"), Some(&owner));
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The ring of Envoy's
+//! [`RING_HASH`](https://www.envoyproxy.io/docs/envoy/latest/intro/arch_overview/upstream/load_balancing/load_balancers#ring-hash)
+//! load balancer with the default `XX_HASH` hash function
+//!
+//! The ring size is scaled so the host with the smallest share of the total weight gets a
+//! whole number of points, between `min_ring_size` and `max_ring_size` points in total. Point
+//! `i` of a host is placed on the 64-bit xxHash of `"{name}_{i}"`, where the node name is the
+//! host address, like `"10.0.0.1:80"`. A key is placed on its 64-bit xxHash.
+
+use super::PointRing;
+use crate::Node;
+
+/// Default minimum number of points on the ring
+pub const DEFAULT_MIN_RING_SIZE: usize = 1024;
+
+/// Default maximum number of points on the ring
+pub const DEFAULT_MAX_RING_SIZE: usize = 1024 * 1024 * 8;
+
+// Points of the hosts, scaled so the host with the smallest share of the total weight gets a
+// whole number of points
+fn host_points<N: Node>(
+    hosts: &[(N, usize)],
+    min_ring_size: usize,
+    max_ring_size: usize,
+) -> Vec<(u64, usize)> {
+    let mut points = Vec::new();
+    if hosts.is_empty() {
+        return points;
+    }
+
+    let total_weight = hosts.iter().map(|(_, w)| w).sum::<usize>() as f64;
+    let min_weight = hosts.iter().map(|(_, w)| *w).min().unwrap_or(1) as f64;
+    let min_normalized_weight = min_weight / total_weight;
+    let scale = ((min_normalized_weight * min_ring_size as f64).ceil() / min_normalized_weight)
+        .min(max_ring_size as f64);
+
+    // Keep running sums across all hosts, so the ring has `ceil(scale)` points in total
+    let mut current_hashes = 0.0;
+    let mut target_hashes = 0.0;
+    for (idx, (node, weight)) in hosts.iter().enumerate() {
+        let node_name = node.name();
+        target_hashes += scale * (*weight as f64 / total_weight);
+
+        let mut i = 0;
+        while current_hashes < target_hashes {
+            let hash_key = format!("{}_{}", node_name, i);
+            let point = xxhash_rust::xxh64::xxh64(hash_key.as_bytes(), 0);
+            points.push((point, idx));
+            i += 1;
+            current_hashes += 1.0;
+        }
+    }
+    points
+}
+
+/// Envoy `RING_HASH` Consistent Hash
+pub struct Envoy<N: Node> {
+    min_ring_size: usize,
+    max_ring_size: usize,
+    ring: PointRing<N, usize, u64>,
+}
+
+impl<N: Node> Envoy<N> {
+    /// Construct with default minimum and maximum ring sizes
+    pub fn new() -> Envoy<N> {
+        Envoy::with_ring_size(DEFAULT_MIN_RING_SIZE, DEFAULT_MAX_RING_SIZE)
+    }
+
+    /// Construct with customized minimum and maximum ring sizes
+    pub fn with_ring_size(min_ring_size: usize, max_ring_size: usize) -> Envoy<N> {
+        assert!(
+            min_ring_size > 0 && min_ring_size <= max_ring_size,
+            "ring size must be between 1 and {}, but got {}",
+            max_ring_size,
+            min_ring_size
+        );
+
+        Envoy {
+            min_ring_size,
+            max_ring_size,
+            ring: PointRing::new(Envoy::<N>::hash),
+        }
+    }
+
+    /// Add a new host with `weight`, then rebuild the ring. A host with the same name is
+    /// replaced in place
+    pub fn add(&mut self, node: &N, weight: usize) {
+        assert!(weight > 0, "weight must be at least 1");
+
+        debug!("Adding node {:?} with weight {}", node.name(), weight);
+        let (min_ring_size, max_ring_size) = (self.min_ring_size, self.max_ring_size);
+        self.ring.add(node, weight, |hosts| {
+            host_points(hosts, min_ring_size, max_ring_size)
+        });
+    }
+
+    /// Remove a host, then rebuild the ring
+    pub fn remove(&mut self, node: &N) {
+        let (min_ring_size, max_ring_size) = (self.min_ring_size, self.max_ring_size);
+        self.ring.remove(node, |hosts| {
+            host_points(hosts, min_ring_size, max_ring_size)
+        });
+    }
+
+    /// Hash of a key on the ring
+    pub fn hash(key: &[u8]) -> u64 {
+        xxhash_rust::xxh64::xxh64(key, 0)
+    }
+
+    /// Get a host by key. Return `None` if no valid host inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.ring.get(key)
+    }
+
+    /// Get a host by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct hosts by key, walking the ring clockwise
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        self.ring.get_n(key, n)
+    }
+
+    /// Get up to `n` distinct hosts by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of points on the ring
+    pub fn num_points(&self) -> usize {
+        self.ring.points.len()
+    }
+
+    /// Number of hosts
+    pub fn len(&self) -> usize {
+        self.ring.servers.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<N: Node> Default for Envoy<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ServerNode;
+
+    #[test]
+    fn ring_size() {
+        let mut ch = Envoy::new();
+        ch.add(&ServerNode::with_address("10.0.0.1:80"), 1);
+        assert_eq!(ch.num_points(), DEFAULT_MIN_RING_SIZE);
+
+        // The smallest host would need more than the maximum ring size. Rounding errors in the
+        // running sums add a point, like they do in Envoy
+        let mut ch = Envoy::with_ring_size(1024, 1500);
+        ch.add(&ServerNode::with_address("10.0.0.1:80"), 1);
+        ch.add(&ServerNode::with_address("10.0.0.2:80"), 1000);
+        assert_eq!(ch.num_points(), 1501);
+    }
+
+    #[test]
+    fn matches_envoy() {
+        let nodes = [
+            ServerNode::with_address("10.0.0.1:80"),
+            ServerNode::with_address("10.0.0.2:80"),
+            ServerNode::with_address("10.0.0.3:80"),
+        ];
+
+        let mut ch = Envoy::new();
+        for (idx, node) in nodes.iter().enumerate() {
+            ch.add(node, idx + 1);
+        }
+        assert_eq!(ch.num_points(), 171 + 342 + 513);
+
+        let expected = [
+            2, 2, 1, 2, 1, 2, 0, 1, 2, 1, 2, 2, 1, 2, 2, 2, 1, 1, 1, 1, 2, 2, 1, 2, 0, 2, 2, 2, 2,
+            1, 2, 2, 2, 1, 2, 1, 2, 1, 2, 2,
+        ];
+        for (i, &idx) in expected.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("key{}", i)), Some(&nodes[idx]));
+        }
+
+        let list = ch.get_str_n("key6", 5);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0], &nodes[0]);
+    }
+}
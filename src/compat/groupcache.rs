@@ -0,0 +1,143 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The ring of Go's [groupcache](https://github.com/golang/groupcache/tree/master/consistenthash)
+//! `consistenthash` package, also used by many Go services
+//!
+//! Replica `i` of a node is placed on the CRC32 hash of `"{i}{name}"`. When replicas of
+//! different nodes share a point, the node added last owns it. A key is placed on its CRC32
+//! hash.
+
+use std::collections::HashMap;
+
+use super::PointRing;
+use crate::Node;
+
+// Points of the nodes, including duplicates. A point shared by several nodes is owned by the
+// node added last
+fn node_points<N: Node>(nodes: &[(N, ())], replicas: usize) -> Vec<(u32, usize)> {
+    let mut points = Vec::with_capacity(nodes.len() * replicas);
+    let mut owners = HashMap::new();
+    for (idx, (node, ())) in nodes.iter().enumerate() {
+        let node_name = node.name();
+        for i in 0..replicas {
+            let point = crc32fast::hash(format!("{}{}", i, node_name).as_bytes());
+            points.push(point);
+            owners.insert(point, idx);
+        }
+    }
+    points
+        .into_iter()
+        .map(|point| (point, owners[&point]))
+        .collect()
+}
+
+/// groupcache `consistenthash.Map` Consistent Hash
+pub struct Groupcache<N: Node> {
+    replicas: usize,
+    ring: PointRing<N, (), u32>,
+}
+
+impl<N: Node> Groupcache<N> {
+    /// Construct with `replicas` points per node
+    pub fn new(replicas: usize) -> Groupcache<N> {
+        assert!(replicas > 0, "number of replicas must be at least 1");
+
+        Groupcache {
+            replicas,
+            ring: PointRing::new(crc32fast::hash),
+        }
+    }
+
+    /// Add a new node. A node with the same name is replaced in place
+    pub fn add(&mut self, node: &N) {
+        debug!("Adding node {:?}", node.name());
+        let replicas = self.replicas;
+        self.ring
+            .add(node, (), |nodes| node_points(nodes, replicas));
+    }
+
+    /// Remove a node, then rebuild the ring as if the remaining nodes were added in order
+    pub fn remove(&mut self, node: &N) {
+        let replicas = self.replicas;
+        self.ring.remove(node, |nodes| node_points(nodes, replicas));
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.ring.get(key)
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key, walking the ring clockwise
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        self.ring.get_n(key, n)
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of points per node
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.ring.servers.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ServerNode;
+
+    #[test]
+    fn matches_groupcache() {
+        let nodes = [
+            ServerNode::with_address("10.0.0.1:80"),
+            ServerNode::with_address("10.0.0.2:80"),
+            ServerNode::with_address("10.0.0.3:80"),
+        ];
+
+        let mut ch = Groupcache::new(50);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+        assert_eq!(ch.ring.points.len(), 150);
+
+        let expected = [
+            0, 2, 1, 0, 1, 2, 1, 0, 2, 0, 0, 2, 0, 1, 0, 2, 0, 1, 0, 2, 0, 1, 0, 2, 0, 1, 1, 2, 0,
+            1, 1, 1, 1, 1, 1, 0, 1, 1, 2, 1,
+        ];
+        for (i, &idx) in expected.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("key{}", i)), Some(&nodes[idx]));
+        }
+
+        ch.remove(&nodes[2]);
+        assert_eq!(ch.ring.points.len(), 100);
+        for (i, &idx) in expected.iter().enumerate() {
+            if idx != 2 {
+                assert_eq!(ch.get_str(&format!("key{}", i)), Some(&nodes[idx]));
+            }
+        }
+        assert_eq!(ch.get_str_n("key0", 3).len(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Ketama](https://github.com/RJ/ketama), as used by libmemcached and most memcached clients
+//!
+//! Every server gets 160 points on a 32-bit ring, scaled by its share of the total weight
+//! (usually the memory of the server). Points are taken four at a time from the Md5 digest of
+//! `"{name}-{index}"`, so node names are expected to be `"host:port"`. A key is placed on the
+//! first 4 bytes of its Md5 digest.
+//!
+//! libmemcached leaves the port out of the server key when it is the default port 11211, so
+//! `"10.0.1.1:11211"` is hashed as `"10.0.1.1-{index}"`; use `Ketama::libmemcached` to share
+//! servers with its clients.
+
+use super::PointRing;
+use crate::Node;
+
+/// Default port of memcached, left out of the server keys by libmemcached
+pub const DEFAULT_PORT: u16 = 11211;
+
+/// Points per server when all servers have the same weight
+pub const POINTS_PER_SERVER: usize = 160;
+
+// Points taken from one Md5 digest
+const POINTS_PER_HASH: usize = 4;
+
+fn point(digest: &[u8], idx: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&digest[idx * 4..idx * 4 + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+// Points of the servers, 40 digests of 4 points per server when all have the same weight
+fn server_points<N: Node>(servers: &[(N, u64)], libmemcached: bool) -> Vec<(u32, usize)> {
+    let mut points = Vec::new();
+    let total_weight = servers.iter().map(|(_, w)| w).sum::<u64>();
+    let num_servers = servers.len();
+    for (idx, (node, weight)) in servers.iter().enumerate() {
+        // Single precision like libmemcached, so the number of points matches exactly
+        let pct = *weight as f32 / total_weight as f32;
+        let scaled = pct * (POINTS_PER_SERVER / POINTS_PER_HASH) as f32 * num_servers as f32;
+        let hashes = (scaled as f64 + 0.0000000001).floor() as usize;
+
+        let node_name = node.name();
+        let default_suffix = format!(":{}", DEFAULT_PORT);
+        let server_key = match node_name.strip_suffix(&default_suffix) {
+            Some(host) if libmemcached => host,
+            _ => &node_name,
+        };
+        for i in 0..hashes {
+            let digest = md5::compute(format!("{}-{}", server_key, i).as_bytes());
+            for h in 0..POINTS_PER_HASH {
+                points.push((point(&digest[..], h), idx));
+            }
+        }
+    }
+    points
+}
+
+/// Ketama Consistent Hash
+pub struct Ketama<N: Node> {
+    ring: PointRing<N, u64, u32>,
+    // Leave the default port out of the server keys
+    libmemcached: bool,
+}
+
+impl<N: Node> Ketama<N> {
+    /// Construct an empty ring
+    pub fn new() -> Ketama<N> {
+        Ketama {
+            ring: PointRing::new(Ketama::<N>::hash),
+            libmemcached: false,
+        }
+    }
+
+    /// Construct an empty ring placing servers like libmemcached, which hashes servers on the
+    /// default port without their port
+    pub fn libmemcached() -> Ketama<N> {
+        Ketama {
+            libmemcached: true,
+            ..Ketama::new()
+        }
+    }
+
+    /// Add a new server with `weight`, then rebuild the ring. A server with the same name is
+    /// replaced in place
+    ///
+    /// Servers with the same weight get 160 points each.
+    pub fn add(&mut self, node: &N, weight: u64) {
+        assert!(weight > 0, "weight must be at least 1");
+
+        debug!("Adding node {:?} with weight {}", node.name(), weight);
+        let libmemcached = self.libmemcached;
+        self.ring
+            .add(node, weight, |servers| server_points(servers, libmemcached));
+    }
+
+    /// Remove a server, then rebuild the ring
+    pub fn remove(&mut self, node: &N) {
+        let libmemcached = self.libmemcached;
+        self.ring
+            .remove(node, |servers| server_points(servers, libmemcached));
+    }
+
+    /// Hash of a key on the ring
+    pub fn hash(key: &[u8]) -> u32 {
+        point(&md5::compute(key)[..], 0)
+    }
+
+    /// Get a server by key. Return `None` if no valid server inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.ring.get(key)
+    }
+
+    /// Get a server by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct servers by key, walking the ring clockwise
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        self.ring.get_n(key, n)
+    }
+
+    /// Get up to `n` distinct servers by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of points on the ring
+    pub fn num_points(&self) -> usize {
+        self.ring.points.len()
+    }
+
+    /// Number of servers
+    pub fn len(&self) -> usize {
+        self.ring.servers.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<N: Node> Default for Ketama<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ServerNode;
+
+    #[test]
+    fn key_hash() {
+        // md5("hello") = 5d41402a...
+        assert_eq!(Ketama::<ServerNode>::hash(b"hello"), 0x2a40415d);
+    }
+
+    #[test]
+    fn equal_weights() {
+        let mut ch = Ketama::new();
+        for port in 11211..11215 {
+            ch.add(&ServerNode::new("127.0.0.1", port), 1024);
+        }
+        assert_eq!(ch.num_points(), 4 * POINTS_PER_SERVER);
+
+        ch.remove(&ServerNode::new("127.0.0.1", 11211));
+        assert_eq!(ch.len(), 3);
+        assert_eq!(ch.num_points(), 3 * POINTS_PER_SERVER);
+    }
+
+    #[test]
+    fn matches_libketama() {
+        let nodes = [
+            ServerNode::new("10.0.1.1", 11211),
+            ServerNode::new("10.0.1.2", 11211),
+            ServerNode::new("10.0.1.3", 11211),
+        ];
+
+        let mut ch = Ketama::new();
+        ch.add(&nodes[0], 600);
+        ch.add(&nodes[1], 300);
+        ch.add(&nodes[2], 100);
+        assert_eq!(ch.num_points(), 288 + 144 + 48);
+        assert_eq!(ch.ring.points.first().unwrap().0, 0x0049a61e);
+        assert_eq!(ch.ring.points.last().unwrap().0, 0xff5c3847);
+
+        let expected = [
+            0, 1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2,
+            2, 0, 1, 0, 1, 0, 0, 1, 1, 0, 1,
+        ];
+        for (i, &idx) in expected.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("key{}", i)), Some(&nodes[idx]));
+        }
+
+        let list = ch.get_str_n("key28", 5);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0], &nodes[2]);
+    }
+
+    #[test]
+    fn matches_libmemcached() {
+        let nodes = [
+            ServerNode::new("10.0.1.1", DEFAULT_PORT),
+            ServerNode::new("10.0.1.2", 11212),
+        ];
+
+        let mut ch = Ketama::libmemcached();
+        ch.add(&nodes[0], 1);
+        ch.add(&nodes[1], 1);
+        assert_eq!(ch.num_points(), 2 * POINTS_PER_SERVER);
+
+        // md5("10.0.1.1-0"), without the default port
+        for point in [0x8e15f0ab, 0x1d1bd3e1, 0x8240cb89, 0x16e23e09] {
+            assert!(ch.ring.points.contains(&(point, 0)));
+        }
+        // md5("10.0.1.1:11211-0")
+        assert!(!ch.ring.points.contains(&(0x90ed8713, 0)));
+
+        // The same as libketama with the default port left out of the name
+        let mut other = Ketama::new();
+        other.add(&ServerNode::with_address("10.0.1.1"), 1);
+        other.add(&nodes[1], 1);
+        for i in 0..100 {
+            let s = format!("key{}", i);
+            let expected = other.get_str(&s).map(Node::name).map(|name| {
+                if name == "10.0.1.1" {
+                    nodes[0].name()
+                } else {
+                    name
+                }
+            });
+            assert_eq!(ch.get_str(&s).map(Node::name), expected);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Rings compatible with other consistent hashing implementations
+//!
+//! Each ring places keys exactly like the implementation it is named after, so Rust services
+//! can share a pool of servers with clients written in other languages.
+
+#[cfg(feature = "xxhash")]
+pub use self::envoy::Envoy;
+#[cfg(feature = "crc32")]
+pub use self::groupcache::Groupcache;
+#[cfg(feature = "md5")]
+pub use self::ketama::Ketama;
+#[cfg(feature = "crc32")]
+pub use self::nginx::Nginx;
+
+#[cfg(feature = "xxhash")]
+pub mod envoy;
+#[cfg(feature = "crc32")]
+pub mod groupcache;
+#[cfg(feature = "md5")]
+pub mod ketama;
+#[cfg(feature = "crc32")]
+pub mod nginx;
+
+#[cfg(any(feature = "md5", feature = "xxhash", feature = "crc32"))]
+use std::collections::HashSet;
+
+#[cfg(any(feature = "md5", feature = "xxhash", feature = "crc32"))]
+use crate::Node;
+
+// Sorted points shared by the rings of this module. Each ring supplies the hash of the keys
+// and generates the points of its servers when the ring is rebuilt
+#[cfg(any(feature = "md5", feature = "xxhash", feature = "crc32"))]
+struct PointRing<N, W, P> {
+    hash: fn(&[u8]) -> P,
+    servers: Vec<(N, W)>,
+    // Points sorted in ascending order, with the index of their server
+    points: Vec<(P, usize)>,
+}
+
+#[cfg(any(feature = "md5", feature = "xxhash", feature = "crc32"))]
+impl<N: Node, W, P: Ord + Copy> PointRing<N, W, P> {
+    fn new(hash: fn(&[u8]) -> P) -> PointRing<N, W, P> {
+        PointRing {
+            hash,
+            servers: Vec::new(),
+            points: Vec::new(),
+        }
+    }
+
+    // Add a new server, or replace the server with the same name in place, then rebuild
+    fn add<F>(&mut self, node: &N, weight: W, points: F)
+    where
+        F: FnOnce(&[(N, W)]) -> Vec<(P, usize)>,
+    {
+        let node_name = node.name();
+        match self.servers.iter().position(|(n, _)| n.name() == node_name) {
+            Some(idx) => self.servers[idx] = (node.clone(), weight),
+            None => self.servers.push((node.clone(), weight)),
+        }
+        self.rebuild(points);
+    }
+
+    // Remove a server, then rebuild
+    fn remove<F>(&mut self, node: &N, points: F)
+    where
+        F: FnOnce(&[(N, W)]) -> Vec<(P, usize)>,
+    {
+        let node_name = node.name();
+        debug!("Removing node {:?}", node_name);
+
+        match self.servers.iter().position(|(n, _)| n.name() == node_name) {
+            Some(idx) => {
+                self.servers.remove(idx);
+                self.rebuild(points);
+            }
+            None => debug!("Node {:?} not exists", node_name),
+        }
+    }
+
+    fn rebuild<F>(&mut self, points: F)
+    where
+        F: FnOnce(&[(N, W)]) -> Vec<(P, usize)>,
+    {
+        self.points = points(&self.servers);
+        self.points.sort_by_key(|&(point, _)| point);
+        debug!(
+            "Rebuilt ring with {} points for {} servers",
+            self.points.len(),
+            self.servers.len()
+        );
+    }
+
+    // Index of the first point at or after the key, wrapping around
+    fn position(&self, key: &[u8]) -> Option<usize> {
+        if self.points.is_empty() {
+            debug!("The container is empty");
+            return None;
+        }
+
+        let h = (self.hash)(key);
+        let idx = self.points.partition_point(|&(point, _)| point < h);
+        Some(idx % self.points.len())
+    }
+
+    fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        let idx = self.position(key)?;
+        let node = &self.servers[self.points[idx].1].0;
+        debug!("Found node {:?} for key {:?}", node.name(), key);
+        Some(node)
+    }
+
+    // Up to `n` distinct servers, walking the ring clockwise from the key
+    fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let idx = match self.position(key) {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+
+        let mut seen = HashSet::with_capacity(n);
+        self.points[idx..]
+            .iter()
+            .chain(self.points[..idx].iter())
+            .filter(|&&(_, server)| seen.insert(server))
+            .take(n.min(self.servers.len()))
+            .map(|&(_, server)| &self.servers[server].0)
+            .collect()
+    }
+}
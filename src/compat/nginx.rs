@@ -0,0 +1,187 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The ring of nginx upstreams using
+//! [`hash $key consistent`](https://nginx.org/en/docs/http/ngx_http_upstream_module.html#hash)
+//!
+//! Every server gets 160 points per unit of weight on a 32-bit ring. The points are a chain of
+//! CRC32 hashes of `host \0 port prev_hash`, where the node name is the server address as
+//! written in the nginx configuration: `"host:port"`, `"host"` or `"unix:/path"`. Points shared
+//! by several servers are kept only once. A key is placed on its CRC32 hash.
+
+use super::PointRing;
+use crate::Node;
+
+/// Points per unit of server weight
+pub const POINTS_PER_WEIGHT: usize = 160;
+
+// Split a server address into host and port, like nginx does
+fn split_address(address: &str) -> (&str, &str) {
+    if address.len() >= 5 && address[..5].eq_ignore_ascii_case("unix:") {
+        return (&address[5..], "");
+    }
+
+    match address.rfind(|c: char| !c.is_ascii_digit()) {
+        Some(idx) if address.as_bytes()[idx] == b':' => (&address[..idx], &address[idx + 1..]),
+        _ => (address, ""),
+    }
+}
+
+// Points of the servers, a chain of CRC32 hashes per server. Points shared by several servers
+// are kept once, owned by the first server
+fn server_points<N: Node>(servers: &[(N, usize)]) -> Vec<(u32, usize)> {
+    let mut points = Vec::new();
+    for (idx, (node, weight)) in servers.iter().enumerate() {
+        let node_name = node.name();
+        let (host, port) = split_address(&node_name);
+
+        let mut base = crc32fast::Hasher::new();
+        base.update(host.as_bytes());
+        base.update(b"\0");
+        base.update(port.as_bytes());
+
+        let mut prev_hash = 0u32;
+        for _ in 0..weight * POINTS_PER_WEIGHT {
+            let mut hasher = base.clone();
+            hasher.update(&prev_hash.to_le_bytes());
+            prev_hash = hasher.finalize();
+            points.push((prev_hash, idx));
+        }
+    }
+
+    points.sort_by_key(|&(point, _)| point);
+    points.dedup_by_key(|&mut (point, _)| point);
+    points
+}
+
+/// nginx `hash ... consistent` Consistent Hash
+pub struct Nginx<N: Node> {
+    ring: PointRing<N, usize, u32>,
+}
+
+impl<N: Node> Nginx<N> {
+    /// Construct an empty ring
+    pub fn new() -> Nginx<N> {
+        Nginx {
+            ring: PointRing::new(Nginx::<N>::hash),
+        }
+    }
+
+    /// Add a new server with `weight`, then rebuild the ring. A server with the same name is
+    /// replaced in place
+    pub fn add(&mut self, node: &N, weight: usize) {
+        assert!(weight > 0, "weight must be at least 1");
+
+        debug!("Adding node {:?} with weight {}", node.name(), weight);
+        self.ring.add(node, weight, server_points);
+    }
+
+    /// Remove a server, then rebuild the ring
+    pub fn remove(&mut self, node: &N) {
+        self.ring.remove(node, server_points);
+    }
+
+    /// Hash of a key on the ring
+    pub fn hash(key: &[u8]) -> u32 {
+        crc32fast::hash(key)
+    }
+
+    /// Get a server by key. Return `None` if no valid server inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.ring.get(key)
+    }
+
+    /// Get a server by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct servers by key, walking the ring clockwise like nginx does when
+    /// the first server is down
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        self.ring.get_n(key, n)
+    }
+
+    /// Get up to `n` distinct servers by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of points on the ring
+    pub fn num_points(&self) -> usize {
+        self.ring.points.len()
+    }
+
+    /// Number of servers
+    pub fn len(&self) -> usize {
+        self.ring.servers.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<N: Node> Default for Nginx<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ServerNode;
+
+    #[test]
+    fn split_addresses() {
+        assert_eq!(split_address("10.0.0.1:8080"), ("10.0.0.1", "8080"));
+        assert_eq!(split_address("[::1]:80"), ("[::1]", "80"));
+        assert_eq!(split_address("backend"), ("backend", ""));
+        assert_eq!(split_address("backend:"), ("backend", ""));
+        assert_eq!(split_address("a:b"), ("a:b", ""));
+        assert_eq!(split_address("UNIX:/tmp/a.sock"), ("/tmp/a.sock", ""));
+    }
+
+    #[test]
+    fn matches_nginx() {
+        let nodes = [
+            ServerNode::with_address("10.0.0.1:8080"),
+            ServerNode::with_address("10.0.0.2:8080"),
+            ServerNode::with_address("backend3"),
+            ServerNode::with_address("unix:/tmp/backend.sock"),
+        ];
+
+        let mut ch = Nginx::new();
+        ch.add(&nodes[0], 1);
+        ch.add(&nodes[1], 2);
+        ch.add(&nodes[2], 1);
+        ch.add(&nodes[3], 1);
+        assert_eq!(ch.num_points(), 5 * POINTS_PER_WEIGHT);
+        assert_eq!(ch.ring.points.first().unwrap().0, 0x00a4637a);
+        assert_eq!(ch.ring.points.last().unwrap().0, 0xff75232d);
+
+        let expected = [
+            1, 3, 0, 0, 0, 0, 0, 1, 0, 1, 2, 1, 0, 2, 2, 0, 3, 2, 2, 1, 1, 1, 1, 1, 2, 1, 3, 3, 2,
+            0, 1, 1, 2, 3, 1, 2, 3, 1, 1, 1,
+        ];
+        for (i, &idx) in expected.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("/item/{}", i)), Some(&nodes[idx]));
+        }
+
+        ch.remove(&nodes[1]);
+        assert_eq!(ch.num_points(), 3 * POINTS_PER_WEIGHT);
+        for (i, &idx) in expected.iter().enumerate() {
+            if idx != 1 {
+                assert_eq!(ch.get_str(&format!("/item/{}", i)), Some(&nodes[idx]));
+            }
+        }
+        assert_eq!(ch.get_str_n("/item/0", 5).len(), 3);
+    }
+}
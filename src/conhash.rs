@@ -6,85 +6,465 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{btree_map::Range, BTreeMap, HashMap, HashSet},
+    iter::Chain,
+    mem,
+    sync::mpsc::{self, Receiver, Sender},
+};
 
-use md5;
+use crate::{
+    hash::RingHasher,
+    node::{NameReplicaKey, VnodeKey},
+    FrozenConsistentHash, Node,
+};
 
-use crate::Node;
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
 
-fn default_md5_hash_fn(input: &[u8]) -> Vec<u8> {
-    let digest = md5::compute(input);
-    digest.to_vec()
+/// A virtual node on the ring
+#[derive(Debug)]
+pub struct VirtualNode<'a, N, P = Vec<u8>> {
+    /// Hashed position on the ring
+    pub hash: &'a P,
+    /// Replica index of the physical node
+    pub replica: usize,
+    /// The physical node
+    pub node: &'a N,
 }
 
+type RingRange<'a, N, P> = Range<'a, P, (usize, N)>;
+
+/// Clockwise iterator over virtual nodes, created by `ConsistentHash::iter_from`
+pub struct RingIter<'a, N, P = Vec<u8>> {
+    inner: Chain<RingRange<'a, N, P>, RingRange<'a, N, P>>,
+}
+
+impl<'a, N, P> Iterator for RingIter<'a, N, P> {
+    type Item = VirtualNode<'a, N, P>;
+
+    fn next(&mut self) -> Option<VirtualNode<'a, N, P>> {
+        self.inner
+            .next()
+            .map(|(hash, (replica, node))| VirtualNode {
+                hash,
+                replica: *replica,
+                node,
+            })
+    }
+}
+
+/// A change of the nodes of a ring, received from `ConsistentHash::subscribe`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MembershipEvent<N> {
+    /// A node was added with `replicas` virtual nodes
+    NodeAdded { node: N, replicas: usize },
+    /// A node was removed with all its virtual nodes
+    NodeRemoved { node: N },
+    /// The number of virtual nodes of a node changed from `old` to `new`
+    ReplicasChanged { node: N, old: usize, new: usize },
+}
+
+// Domain of a node at a level of `ConsistentHash::get_n_across_domains`
+#[derive(PartialEq, Eq, Hash)]
+enum DomainKey {
+    // The first entries of the node's failure domain
+    Path(Vec<String>),
+    // The name of a node not placed at the level, in a domain of its own
+    Own(String),
+}
+
+enum Op<N> {
+    Add(N, usize),
+    AddWeighted(N, f64),
+    Remove(N),
+}
+
+// Positions of the virtual nodes a batch inserts, hashed before the ring is changed
+struct HashedBatch<N, P> {
+    // Of each node added with a number of replicas, in order
+    added: Vec<Vec<P>>,
+    // Of the rebalanced weighted nodes
+    rebalanced: Vec<(N, Vec<P>)>,
+}
+
+/// A batch of changes, staged by the closure of `ConsistentHash::update`
+pub struct Transaction<N> {
+    ops: Vec<Op<N>>,
+    aborted: bool,
+}
+
+impl<N: Node> Transaction<N> {
+    /// Add a new node, see `ConsistentHash::add`
+    pub fn add(&mut self, node: &N, num_replicas: usize) {
+        self.ops.push(Op::Add(node.clone(), num_replicas));
+    }
+
+    /// Add a new node with a relative `weight`, see `ConsistentHash::add_weighted`
+    pub fn add_weighted(&mut self, node: &N, weight: f64) {
+        assert!(
+            weight.is_finite() && weight > 0.0,
+            "weight must be a positive number, but got {}",
+            weight
+        );
+        self.ops.push(Op::AddWeighted(node.clone(), weight));
+    }
+
+    /// Remove a node, see `ConsistentHash::remove`
+    pub fn remove(&mut self, node: &N) {
+        self.ops.push(Op::Remove(node.clone()));
+    }
+
+    /// Discard the batch, leaving the ring untouched
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+}
+
+/// Default number of virtual nodes shared by the weighted nodes of a ring
+pub const DEFAULT_VNODE_BUDGET: usize = 1000;
+
 /// Consistent Hash
-pub struct ConsistentHash<N: Node> {
-    hash_fn: fn(&[u8]) -> Vec<u8>,
-    nodes: BTreeMap<Vec<u8>, N>,
-    replicas: HashMap<String, usize>,
+pub struct ConsistentHash<
+    N: Node,
+    H: RingHasher = fn(&[u8]) -> Vec<u8>,
+    K: VnodeKey<N> = NameReplicaKey,
+> {
+    hasher: H,
+    vnode_key: K,
+    nodes: BTreeMap<H::Position, (usize, N)>,
+    // Positions of the virtual nodes of each node, by replica index
+    positions: HashMap<String, Vec<H::Position>>,
+    weights: HashMap<String, (N, f64)>,
+    vnode_budget: usize,
+    subscribers: Vec<Sender<MembershipEvent<N>>>,
+    // Events kept for the subscribers of a `SharedConsistentHash`, while recording
+    recorded: Option<Vec<MembershipEvent<N>>>,
+    epoch: u64,
+}
+
+// Clones start without subscribers, see `ConsistentHash::subscribe`
+impl<N, H, K> Clone for ConsistentHash<N, H, K>
+where
+    N: Node,
+    H: RingHasher + Clone,
+    K: VnodeKey<N> + Clone,
+{
+    fn clone(&self) -> Self {
+        ConsistentHash {
+            hasher: self.hasher.clone(),
+            vnode_key: self.vnode_key.clone(),
+            nodes: self.nodes.clone(),
+            positions: self.positions.clone(),
+            weights: self.weights.clone(),
+            vnode_budget: self.vnode_budget,
+            subscribers: Vec::new(),
+            recorded: None,
+            epoch: self.epoch,
+        }
+    }
 }
 
 impl<N: Node> ConsistentHash<N> {
     /// Construct with default hash function (Md5)
+    #[cfg(feature = "md5")]
     pub fn new() -> ConsistentHash<N> {
         ConsistentHash::with_hash(default_md5_hash_fn)
     }
 
     /// Construct with customized hash function
     pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> ConsistentHash<N> {
+        ConsistentHash::with_hasher(hash_fn)
+    }
+}
+
+impl<N: Node, H: RingHasher> ConsistentHash<N, H> {
+    /// Construct with customized `RingHasher`
+    pub fn with_hasher(hasher: H) -> ConsistentHash<N, H> {
+        ConsistentHash::with_hasher_and_vnode_key(hasher, NameReplicaKey)
+    }
+}
+
+impl<N: Node, H: RingHasher, K: VnodeKey<N>> ConsistentHash<N, H, K> {
+    /// Construct with customized `RingHasher` and derivation of virtual node keys
+    pub fn with_hasher_and_vnode_key(hasher: H, vnode_key: K) -> ConsistentHash<N, H, K> {
         ConsistentHash {
-            hash_fn,
+            hasher,
+            vnode_key,
             nodes: BTreeMap::new(),
-            replicas: HashMap::new(),
+            positions: HashMap::new(),
+            weights: HashMap::new(),
+            vnode_budget: DEFAULT_VNODE_BUDGET,
+            subscribers: Vec::new(),
+            recorded: None,
+            epoch: 0,
+        }
+    }
+
+    /// The hasher placing keys and virtual nodes
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    /// The derivation of virtual node keys
+    pub fn vnode_key(&self) -> &K {
+        &self.vnode_key
+    }
+
+    /// Subscribe to changes of the nodes
+    ///
+    /// Every node added or removed, and every change of a node's number of replicas, including
+    /// the ones caused by rebalancing weighted nodes, is sent to the receiver. Adding a node
+    /// again with the same number of replicas is not a change. Clones of the ring don't send to
+    /// the subscribers, and a subscription ends when its receiver is dropped.
+    pub fn subscribe(&mut self) -> Receiver<MembershipEvent<N>> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn notify<F>(&mut self, event: F)
+    where
+        F: FnOnce() -> MembershipEvent<N>,
+    {
+        if self.subscribers.is_empty() && self.recorded.is_none() {
+            return;
+        }
+
+        let event = event();
+        self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push(event);
+        }
+    }
+
+    // Move the subscribers out of the ring
+    pub(crate) fn take_subscribers(&mut self) -> Vec<Sender<MembershipEvent<N>>> {
+        mem::take(&mut self.subscribers)
+    }
+
+    // Keep the events of the following changes, until `take_recorded`
+    pub(crate) fn record(&mut self) {
+        self.recorded = Some(Vec::new());
+    }
+
+    // Stop recording. Return the events recorded since `record`
+    pub(crate) fn take_recorded(&mut self) -> Vec<MembershipEvent<N>> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    // Notify the subscribers of a node that had `old` replicas before it was inserted. Return
+    // `true` if the node changed
+    fn notify_inserted(&mut self, node: &N, old: Option<usize>, new: usize) -> bool {
+        match old {
+            None => self.notify(|| MembershipEvent::NodeAdded {
+                node: node.clone(),
+                replicas: new,
+            }),
+            Some(old) if old != new => self.notify(|| MembershipEvent::ReplicasChanged {
+                node: node.clone(),
+                old,
+                new,
+            }),
+            Some(_) => return false,
+        }
+        true
+    }
+
+    /// Epoch of the ring, starting from `0`
+    ///
+    /// The epoch is bumped by every mutation that adds, replaces or removes a node or changes a
+    /// node's number of replicas, once per call of `add`, `add_weighted`, `set_vnode_budget`,
+    /// `remove` or `update`. Adding a node again replaces it, so it bumps the epoch even with
+    /// the same number of replicas or weight.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn bump_epoch(&mut self, changed: bool) {
+        if changed {
+            self.epoch += 1;
+            debug!("Ring epoch is {}", self.epoch);
         }
     }
 
     /// Add a new node
     pub fn add(&mut self, node: &N, num_replicas: usize) {
+        let old = self.insert_node(node, num_replicas);
+        self.notify_inserted(node, old, num_replicas);
+
+        // A fixed number of replicas replaces the weight
+        if self.weights.remove(&node.name()).is_some() {
+            self.rebalance();
+        }
+        self.bump_epoch(true);
+    }
+
+    /// Add a new node with a relative `weight`
+    ///
+    /// Weighted nodes share the virtual node budget (see `set_vnode_budget`) in proportion to
+    /// their weights, and every weighted node has at least one replica. Replica counts of all
+    /// weighted nodes are recomputed whenever a weight changes.
+    pub fn add_weighted(&mut self, node: &N, weight: f64) {
+        assert!(
+            weight.is_finite() && weight > 0.0,
+            "weight must be a positive number, but got {}",
+            weight
+        );
+
+        debug!("Adding node {:?} with weight {}", node.name(), weight);
+        self.replace_node(node);
+        self.weights.insert(node.name(), (node.clone(), weight));
+        self.rebalance();
+        self.bump_epoch(true);
+    }
+
+    /// Set the number of virtual nodes shared by the weighted nodes
+    pub fn set_vnode_budget(&mut self, vnode_budget: usize) {
+        self.vnode_budget = vnode_budget;
+        let changed = self.rebalance();
+        self.bump_epoch(changed);
+    }
+
+    /// Number of virtual nodes shared by the weighted nodes
+    pub fn vnode_budget(&self) -> usize {
+        self.vnode_budget
+    }
+
+    /// Weight of a node. Return `None` if the node is not weighted
+    pub fn weight(&self, node: &N) -> Option<f64> {
+        self.weights.get(&node.name()).map(|(_, weight)| *weight)
+    }
+
+    /// Number of replicas (virtual nodes) of a node. Return `None` if the node not exists
+    pub fn replicas(&self, node: &N) -> Option<usize> {
+        self.positions.get(&node.name()).map(Vec::len)
+    }
+
+    // Recompute replica counts of the weighted nodes from their share of the total weight.
+    // Return `true` if any node changed
+    fn rebalance(&mut self) -> bool {
+        let weighted = self.weights.values().collect::<Vec<_>>();
+        let changed = self.rebalanced(weighted, |name| self.positions.get(name).map(Vec::len));
+
+        let mut any_changed = false;
+        for (node, num_replicas) in changed {
+            let old = self.insert_node(&node, num_replicas);
+            any_changed |= self.notify_inserted(&node, old, num_replicas);
+        }
+        any_changed
+    }
+
+    // Weighted nodes whose number of replicas differs from `replicas`, with their new number
+    fn rebalanced<F>(&self, mut weighted: Vec<&(N, f64)>, replicas: F) -> Vec<(N, usize)>
+    where
+        F: Fn(&str) -> Option<usize>,
+    {
+        // In the order of names, so colliding positions are owned alike in every process
+        weighted.sort_by_key(|(node, _)| node.name());
+
+        let total_weight = weighted.iter().map(|(_, weight)| weight).sum::<f64>();
+        weighted
+            .into_iter()
+            .filter_map(|(node, weight)| {
+                let share = self.vnode_budget as f64 * weight / total_weight;
+                let num_replicas = (share.round() as usize).max(1);
+                if replicas(&node.name()) == Some(num_replicas) {
+                    None
+                } else {
+                    Some((node.clone(), num_replicas))
+                }
+            })
+            .collect()
+    }
+
+    // Replace a node in its virtual nodes, keeping their positions
+    fn replace_node(&mut self, node: &N) {
+        if let Some(positions) = self.positions.get(&node.name()) {
+            for key in positions {
+                if let Some((_, n)) = self.nodes.get_mut(key) {
+                    *n = node.clone();
+                }
+            }
+        }
+    }
+
+    // Insert a node, replacing the node with the same name. Return its previous number of
+    // replicas
+    fn insert_node(&mut self, node: &N, num_replicas: usize) -> Option<usize> {
+        let positions = self.hash_replicas(node, num_replicas);
+        self.place_node(node, positions)
+    }
+
+    // Positions of the virtual nodes of a node, without changing the ring
+    fn hash_replicas(&self, node: &N, num_replicas: usize) -> Vec<H::Position> {
+        (0..num_replicas)
+            .map(|replica| {
+                let node_ident = self.vnode_key.vnode_key(node, replica);
+                let key = self.hasher.hash(&node_ident);
+                debug!(
+                    "Hashed node {:?} of replica {}, hashed key is {:?}",
+                    node.name(),
+                    replica,
+                    key
+                );
+                key
+            })
+            .collect()
+    }
+
+    // Insert a node at the positions of its replicas, replacing the node with the same name.
+    // Return its previous number of replicas
+    fn place_node(&mut self, node: &N, positions: Vec<H::Position>) -> Option<usize> {
         let node_name = node.name();
-        debug!("Adding node {:?} with {} replicas", node_name, num_replicas);
+        debug!(
+            "Adding node {:?} with {} replicas",
+            node_name,
+            positions.len()
+        );
 
         // Remove it first
-        self.remove(node);
-
-        self.replicas.insert(node_name.clone(), num_replicas);
-        for replica in 0..num_replicas {
-            let node_ident = format!("{}:{}", node_name, replica);
-            let key = (self.hash_fn)(node_ident.as_bytes());
-            debug!(
-                "Adding node {:?} of replica {}, hashed key is {:?}",
-                node.name(),
-                replica,
-                key
-            );
+        let old = self.remove_node(node);
 
-            self.nodes.insert(key, node.clone());
+        for (replica, key) in positions.iter().enumerate() {
+            self.nodes.insert(key.clone(), (replica, node.clone()));
         }
+        self.positions.insert(node_name, positions);
+        old
     }
 
-    /// Get a node by key. Return `None` if no valid node inside
-    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
-        if self.nodes.is_empty() {
-            debug!("The container is empty");
-            return None;
+    /// Iterate virtual nodes clockwise, starting from the successor of the key's position
+    ///
+    /// The iterator wraps around the ring once, so every virtual node is visited exactly once.
+    pub fn iter_from<'a>(&'a self, key: &[u8]) -> RingIter<'a, N, H::Position> {
+        let hashed_key = self.hasher.hash(key);
+        debug!("Walking from key {:?}, hashed key is {:?}", key, hashed_key);
+
+        RingIter {
+            inner: self
+                .nodes
+                .range(hashed_key.clone()..)
+                .chain(self.nodes.range(..hashed_key)),
         }
+    }
 
-        let hashed_key = (self.hash_fn)(key);
-        debug!("Getting key {:?}, hashed key is {:?}", key, hashed_key);
+    /// Iterate virtual nodes clockwise, starting from the successor of the string key's position
+    pub fn iter_from_str<'a>(&'a self, key: &str) -> RingIter<'a, N, H::Position> {
+        self.iter_from(key.as_bytes())
+    }
 
-        let entry = self.nodes.range(hashed_key..).next();
-        if let Some((_k, v)) = entry {
-            debug!("Found node {:?}", v.name());
-            return Some(v);
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        match self.iter_from(key).next() {
+            Some(vnode) => {
+                debug!("Found node {:?}", vnode.node.name());
+                Some(vnode.node)
+            }
+            None => {
+                debug!("The container is empty");
+                None
+            }
         }
-
-        // Back to the first one
-        debug!("Search to the end, coming back to the head ...");
-        let first = self.nodes.iter().next();
-        debug_assert!(first.is_some());
-        let (_k, v) = first.unwrap();
-        debug!("Found node {:?}", v.name());
-        Some(v)
     }
 
     /// Get a node by string key
@@ -92,65 +472,368 @@ impl<N: Node> ConsistentHash<N> {
         self.get(key.as_bytes())
     }
 
+    /// Get a node by key with the epoch of the ring. Return `None` if no valid node inside
+    pub fn get_versioned<'a>(&'a self, key: &[u8]) -> Option<(&'a N, u64)> {
+        self.get(key).map(|node| (node, self.epoch))
+    }
+
+    /// Get a node by string key with the epoch of the ring
+    pub fn get_str_versioned<'a>(&'a self, key: &str) -> Option<(&'a N, u64)> {
+        self.get_versioned(key.as_bytes())
+    }
+
+    /// Get the first node by key that satisfies `predicate`, continuing clockwise past the ones
+    /// that don't. Return `None` if no valid node inside
+    ///
+    /// This routes around unavailable nodes without removing them from the ring.
+    pub fn get_with<'a, P>(&'a self, key: &[u8], mut predicate: P) -> Option<&'a N>
+    where
+        P: FnMut(&N) -> bool,
+    {
+        for vnode in self.iter_from(key) {
+            if predicate(vnode.node) {
+                debug!("Found node {:?}", vnode.node.name());
+                return Some(vnode.node);
+            }
+            debug!("Skipping node {:?}", vnode.node.name());
+        }
+
+        debug!("No node satisfies the predicate");
+        None
+    }
+
+    /// Get the first node by string key that satisfies `predicate`
+    pub fn get_str_with<'a, P>(&'a self, key: &str, predicate: P) -> Option<&'a N>
+    where
+        P: FnMut(&N) -> bool,
+    {
+        self.get_with(key.as_bytes(), predicate)
+    }
+
+    /// Get a node by key, skipping nodes whose `Node::name()` is in `excluded`
+    pub fn get_excluding<'a>(&'a self, key: &[u8], excluded: &HashSet<String>) -> Option<&'a N> {
+        if excluded.is_empty() {
+            return self.get(key);
+        }
+        self.get_with(key, |node| !excluded.contains(&node.name()))
+    }
+
+    /// Get a node by string key, skipping nodes whose `Node::name()` is in `excluded`
+    pub fn get_str_excluding<'a>(&'a self, key: &str, excluded: &HashSet<String>) -> Option<&'a N> {
+        self.get_excluding(key.as_bytes(), excluded)
+    }
+
+    /// Get up to `n` distinct nodes by key, in clockwise order starting from the key's position
+    ///
+    /// Virtual nodes are deduplicated by `Node::name()`, so the result is a preference list of
+    /// physical nodes. Return fewer than `n` nodes if there are not enough distinct nodes inside.
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let mut result = Vec::with_capacity(n);
+        if n == 0 || self.nodes.is_empty() {
+            return result;
+        }
+
+        let mut names = HashSet::with_capacity(n);
+        for vnode in self.iter_from(key) {
+            if names.insert(vnode.node.name()) {
+                debug!("Found node {:?}", vnode.node.name());
+                result.push(vnode.node);
+                if result.len() == n || names.len() == self.positions.len() {
+                    break;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Get up to `n` nodes by key that are all in distinct failure domains at `level`,
+    /// in clockwise order starting from the key's position
+    ///
+    /// Two nodes share a domain at `level` when the first `level + 1` entries of their
+    /// `Node::failure_domain()` are equal, so level `0` spreads the nodes over regions in the
+    /// example of `Node::failure_domain()`. Return fewer than `n` nodes if there are not enough
+    /// distinct domains inside.
+    pub fn get_n_across_domains<'a>(&'a self, key: &[u8], n: usize, level: usize) -> Vec<&'a N> {
+        let mut result = Vec::with_capacity(n);
+        if n == 0 {
+            return result;
+        }
+
+        let mut names = HashSet::new();
+        let mut domains = HashSet::with_capacity(n);
+        for vnode in self.iter_from(key) {
+            let node_name = vnode.node.name();
+            if !names.insert(node_name.clone()) {
+                continue;
+            }
+
+            let mut domain = vnode.node.failure_domain();
+            let domain = if domain.len() > level {
+                domain.truncate(level + 1);
+                DomainKey::Path(domain)
+            } else {
+                DomainKey::Own(node_name)
+            };
+
+            if domains.insert(domain) {
+                debug!("Found node {:?}", vnode.node.name());
+                result.push(vnode.node);
+                if result.len() == n {
+                    break;
+                }
+            } else {
+                debug!("Skipping node {:?} in a used domain", vnode.node.name());
+            }
+
+            if names.len() == self.positions.len() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// Get up to `n` nodes by string key that are all in distinct failure domains at `level`
+    pub fn get_str_n_across_domains<'a>(&'a self, key: &str, n: usize, level: usize) -> Vec<&'a N> {
+        self.get_n_across_domains(key.as_bytes(), n, level)
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
     /// Get a node by key. Return `None` if no valid node inside
     pub fn get_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut N> {
         let hashed_key = self.get_node_hashed_key(key);
-        hashed_key.and_then(move |k| self.nodes.get_mut(&k))
+        hashed_key.and_then(move |k| self.nodes.get_mut(&k).map(|(_, n)| n))
     }
 
     // Get a node's hashed key by key. Return `None` if no valid node inside
-    fn get_node_hashed_key(&self, key: &[u8]) -> Option<Vec<u8>> {
-        if self.nodes.is_empty() {
-            debug!("The container is empty");
-            return None;
+    fn get_node_hashed_key(&self, key: &[u8]) -> Option<H::Position> {
+        self.iter_from(key).next().map(|vnode| vnode.hash.clone())
+    }
+
+    /// Get a node by string key
+    pub fn get_str_mut<'a>(&'a mut self, key: &str) -> Option<&'a mut N> {
+        self.get_mut(key.as_bytes())
+    }
+
+    /// Apply a batch of changes staged by `f` in a single pass
+    ///
+    /// Nothing is applied if `f` calls `Transaction::abort`, or if `f` or the hasher panics.
+    /// Weighted nodes are rebalanced once, subscribers are notified after the whole batch of
+    /// the net change of each node, and the epoch is bumped once if any node changed. Return
+    /// `true` if the batch was applied.
+    pub fn update<F>(&mut self, f: F) -> bool
+    where
+        F: FnOnce(&mut Transaction<N>),
+    {
+        let mut tx = Transaction {
+            ops: Vec::new(),
+            aborted: false,
+        };
+        f(&mut tx);
+        if tx.aborted {
+            debug!("Transaction of {} changes aborted", tx.ops.len());
+            return false;
         }
+        debug!("Applying transaction of {} changes", tx.ops.len());
 
-        let hashed_key = (self.hash_fn)(key);
-        debug!("Getting key {:?}, hashed key is {:?}", key, hashed_key);
+        // Replicas of every node before the batch, to find the net changes afterwards
+        let before = self
+            .positions
+            .iter()
+            .map(|(name, positions)| (name.clone(), positions.len()))
+            .collect::<HashMap<_, _>>();
 
-        let entry = self.nodes.range(hashed_key..).next();
-        if let Some((k, v)) = entry {
-            debug!("Found node {:?}", v.name());
-            return Some(k.clone());
+        // Hash first, so the ring is untouched if the hasher panics
+        let hashed = self.hash_batch(&tx.ops);
+        let touched = self.apply(tx.ops, hashed);
+        let changed = self.notify_changes(&before, touched);
+        self.bump_epoch(changed);
+        true
+    }
+
+    // Hash the virtual nodes a batch inserts, without changing the ring
+    fn hash_batch(&self, ops: &[Op<N>]) -> HashedBatch<N, H::Position> {
+        // Replicas and weights of the nodes changed by the batch, before rebalancing
+        let mut replicas = HashMap::new();
+        let mut weights: HashMap<String, Option<(N, f64)>> = HashMap::new();
+        let mut added = Vec::new();
+        let mut rebalance = false;
+        for op in ops {
+            let (node_name, weight) = match *op {
+                Op::Add(ref node, num_replicas) => {
+                    added.push(self.hash_replicas(node, num_replicas));
+                    replicas.insert(node.name(), Some(num_replicas));
+                    (node.name(), None)
+                }
+                Op::AddWeighted(ref node, weight) => (node.name(), Some((node.clone(), weight))),
+                Op::Remove(ref node) => {
+                    replicas.insert(node.name(), None);
+                    (node.name(), None)
+                }
+            };
+            let was_weighted = match weights.get(&node_name) {
+                Some(old) => old.is_some(),
+                None => self.weights.contains_key(&node_name),
+            };
+            rebalance |= was_weighted || weight.is_some();
+            weights.insert(node_name, weight);
+        }
+        if !rebalance {
+            return HashedBatch {
+                added,
+                rebalanced: Vec::new(),
+            };
         }
 
-        // Back to the first one
-        debug!("Search to the end, coming back to the head ...");
-        let first = self.nodes.iter().next();
-        debug_assert!(first.is_some());
-        let (k, v) = first.unwrap();
-        debug!("Found node {:?}", v.name());
-        Some(k.clone())
+        let weighted = self
+            .weights
+            .iter()
+            .filter(|(name, _)| !weights.contains_key(*name))
+            .map(|(_, weighted)| weighted)
+            .chain(weights.values().flatten())
+            .collect::<Vec<_>>();
+        let rebalanced = self
+            .rebalanced(weighted, |name| match replicas.get(name) {
+                Some(&num_replicas) => num_replicas,
+                None => self.positions.get(name).map(Vec::len),
+            })
+            .into_iter()
+            .map(|(node, num_replicas)| {
+                let positions = self.hash_replicas(&node, num_replicas);
+                (node, positions)
+            })
+            .collect();
+        HashedBatch { added, rebalanced }
     }
 
-    /// Get a node by string key
-    pub fn get_str_mut<'a>(&'a mut self, key: &str) -> Option<&'a mut N> {
-        self.get_mut(key.as_bytes())
+    // Apply the changes of a batch, with the positions hashed by `hash_batch`. Return the nodes
+    // changed, in order
+    fn apply(&mut self, ops: Vec<Op<N>>, hashed: HashedBatch<N, H::Position>) -> Vec<N> {
+        let mut added = hashed.added.into_iter();
+        let mut touched = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                Op::Add(node, _) => {
+                    let positions = added.next().expect("positions hashed for every addition");
+                    self.place_node(&node, positions);
+                    self.weights.remove(&node.name());
+                    touched.push(node);
+                }
+                Op::AddWeighted(node, weight) => {
+                    self.replace_node(&node);
+                    self.weights.insert(node.name(), (node.clone(), weight));
+                    touched.push(node);
+                }
+                Op::Remove(node) => {
+                    self.remove_node(&node);
+                    self.weights.remove(&node.name());
+                    touched.push(node);
+                }
+            }
+        }
+        for (node, positions) in hashed.rebalanced {
+            self.place_node(&node, positions);
+        }
+        touched
+    }
+
+    // Notify the subscribers of the nodes changed since `before`, the nodes of the batch in
+    // order followed by the rebalanced weighted nodes. Return `true` if any node changed or a
+    // node of the batch was replaced
+    fn notify_changes(&mut self, before: &HashMap<String, usize>, touched: Vec<N>) -> bool {
+        let mut nodes = Vec::with_capacity(touched.len());
+        let mut indices = HashMap::with_capacity(touched.len());
+        for node in touched {
+            match indices.get(&node.name()) {
+                Some(&idx) => nodes[idx] = node,
+                None => {
+                    indices.insert(node.name(), nodes.len());
+                    nodes.push(node);
+                }
+            }
+        }
+
+        let mut rebalanced = self
+            .weights
+            .values()
+            .filter(|(node, _)| !indices.contains_key(&node.name()))
+            .map(|(node, _)| node.clone())
+            .collect::<Vec<_>>();
+        rebalanced.sort_by_key(|node| node.name());
+        let num_touched = nodes.len();
+        nodes.extend(rebalanced);
+
+        let mut changed = false;
+        for (idx, node) in nodes.into_iter().enumerate() {
+            let node_name = node.name();
+            let old = before.get(&node_name).copied();
+            match self.positions.get(&node_name).map(Vec::len) {
+                Some(new) => {
+                    let notified = self.notify_inserted(&node, old, new);
+                    changed |= notified || idx < num_touched;
+                }
+                None if old.is_some() => {
+                    self.notify(|| MembershipEvent::NodeRemoved { node });
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+        changed
     }
 
     /// Remove a node with all replicas (virtual nodes)
     pub fn remove(&mut self, node: &N) {
+        let mut changed = false;
+        if self.remove_node(node).is_some() {
+            self.notify(|| MembershipEvent::NodeRemoved { node: node.clone() });
+            changed = true;
+        }
+
+        if self.weights.remove(&node.name()).is_some() {
+            changed |= self.rebalance();
+        }
+        self.bump_epoch(changed);
+    }
+
+    // Remove a node. Return its number of replicas
+    fn remove_node(&mut self, node: &N) -> Option<usize> {
         let node_name = node.name();
         debug!("Removing node {:?}", node_name);
 
-        let num_replicas = match self.replicas.remove(&node_name) {
+        let positions = match self.positions.remove(&node_name) {
             Some(val) => {
-                debug!("Node {:?} has {} replicas", node_name, val);
+                debug!("Node {:?} has {} replicas", node_name, val.len());
                 val
             }
             None => {
                 debug!("Node {:?} not exists", node_name);
-                return;
+                return None;
             }
         };
 
-        debug!("Node {:?} replicas {}", node_name, num_replicas);
-
-        for replica in 0..num_replicas {
-            let node_ident = format!("{}:{}", node.name(), replica);
-            let key = (self.hash_fn)(node_ident.as_bytes());
-            self.nodes.remove(&key);
+        let num_replicas = positions.len();
+        for key in positions {
+            // Keep a virtual node of another node that took the same position
+            if self
+                .nodes
+                .get(&key)
+                .is_some_and(|(_, n)| n.name() == node_name)
+            {
+                self.nodes.remove(&key);
+            }
         }
+        Some(num_replicas)
+    }
+
+    /// Freeze into an immutable ring that places keys the same way
+    pub fn freeze(self) -> FrozenConsistentHash<N, H> {
+        FrozenConsistentHash::new(self.hasher, self.nodes)
     }
 
     /// Number of nodes
@@ -158,19 +841,25 @@ impl<N: Node> ConsistentHash<N> {
         self.nodes.len()
     }
 
+    /// Number of physical nodes
+    pub fn num_nodes(&self) -> usize {
+        self.positions.len()
+    }
+
     /// Is empty
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 }
 
+#[cfg(feature = "md5")]
 impl<N: Node> Default for ConsistentHash<N> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "md5"))]
 mod test {
     use super::*;
 
@@ -187,6 +876,7 @@ mod test {
     }
 
     impl ServerNode {
+        #[allow(clippy::redundant_field_names)]
         fn new(host: &str, port: u16) -> ServerNode {
             ServerNode {
                 host: host.to_owned(),
@@ -275,6 +965,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn get_exact_node() {
         let mut ch = ConsistentHash::new();
         const NODES: usize = 1000;
@@ -294,4 +985,691 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn iter_from_walks_whole_ring() {
+        let nodes = [
+            ServerNode::new("localhost", 12345),
+            ServerNode::new("localhost", 12346),
+            ServerNode::new("localhost", 12347),
+        ];
+
+        const REPLICAS: usize = 10;
+
+        let mut ch = ConsistentHash::new();
+        for node in nodes.iter() {
+            ch.add(node, REPLICAS);
+        }
+
+        assert_eq!(
+            ConsistentHash::<ServerNode>::new()
+                .iter_from_str("hello")
+                .count(),
+            0
+        );
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            let vnodes = ch.iter_from_str(&s).collect::<Vec<_>>();
+            assert_eq!(vnodes.len(), ch.len());
+            assert_eq!(Some(vnodes[0].node), ch.get_str(&s));
+
+            // Positions increase clockwise with exactly one wrap around
+            let wraps = vnodes.windows(2).filter(|w| w[0].hash >= w[1].hash).count();
+            assert!(wraps <= 1);
+
+            for vnode in vnodes.iter() {
+                let ident = format!("{}:{}", vnode.node.name(), vnode.replica);
+                assert_eq!(vnode.hash, &md5::compute(ident.as_bytes())[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn get_with_skips_nodes() {
+        let nodes = [
+            ServerNode::new("localhost", 12345),
+            ServerNode::new("localhost", 12346),
+            ServerNode::new("localhost", 12347),
+        ];
+
+        let mut ch = ConsistentHash::new();
+        for node in nodes.iter() {
+            ch.add(node, 20);
+        }
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            let first = ch.get_str(&s).unwrap();
+            assert_eq!(ch.get_str_with(&s, |_| true), Some(first));
+            assert_eq!(ch.get_str_with(&s, |_| false), None);
+
+            // Skipping the owner falls through to the next node clockwise
+            let expected = ch.get_str_n(&s, 2)[1];
+            assert_eq!(ch.get_str_with(&s, |n| n != first), Some(expected));
+
+            let mut excluded = HashSet::new();
+            assert_eq!(ch.get_str_excluding(&s, &excluded), Some(first));
+            excluded.insert(first.name());
+            assert_eq!(ch.get_str_excluding(&s, &excluded), Some(expected));
+            excluded.insert(expected.name());
+            let last = ch.get_str_excluding(&s, &excluded).unwrap();
+            assert!(last != first && last != expected);
+        }
+
+        // The ring itself is untouched
+        assert_eq!(ch.len(), nodes.len() * 20);
+    }
+
+    #[test]
+    fn weighted_nodes() {
+        let small = ServerNode::new("localhost", 12345);
+        let medium = ServerNode::new("localhost", 12346);
+        let large = ServerNode::new("localhost", 12347);
+
+        let mut ch = ConsistentHash::new();
+        ch.set_vnode_budget(100);
+        ch.add_weighted(&small, 1.0);
+        assert_eq!(ch.replicas(&small), Some(100));
+
+        ch.add_weighted(&medium, 1.0);
+        ch.add_weighted(&large, 2.0);
+        assert_eq!(ch.replicas(&small), Some(25));
+        assert_eq!(ch.replicas(&medium), Some(25));
+        assert_eq!(ch.replicas(&large), Some(50));
+        assert_eq!(ch.weight(&large), Some(2.0));
+        assert_eq!(ch.len(), 100);
+
+        // Changing a weight recomputes every weighted node
+        ch.add_weighted(&large, 6.0);
+        assert_eq!(ch.replicas(&small), Some(13));
+        assert_eq!(ch.replicas(&large), Some(75));
+
+        // Fixed replicas don't count in the budget
+        ch.add(&medium, 10);
+        assert_eq!(ch.weight(&medium), None);
+        assert_eq!(ch.replicas(&medium), Some(10));
+        assert_eq!(ch.replicas(&small), Some(14));
+        assert_eq!(ch.replicas(&large), Some(86));
+
+        ch.remove(&large);
+        assert_eq!(ch.replicas(&large), None);
+        assert_eq!(ch.replicas(&small), Some(100));
+        assert_eq!(ch.len(), 110);
+
+        ch.set_vnode_budget(0);
+        assert_eq!(ch.replicas(&small), Some(1));
+
+        // The ring matches one built with the same replica counts by hand
+        let mut manual = ConsistentHash::new();
+        manual.add(&small, 1);
+        manual.add(&medium, 10);
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), manual.get_str(&s));
+        }
+    }
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct ZonedNode {
+        zone: &'static str,
+        rack: &'static str,
+        port: u16,
+    }
+
+    impl Node for ZonedNode {
+        fn name(&self) -> String {
+            format!("{}:{}", self.rack, self.port)
+        }
+
+        fn failure_domain(&self) -> Vec<String> {
+            vec![self.zone.to_owned(), self.rack.to_owned()]
+        }
+    }
+
+    #[test]
+    fn get_n_across_domains() {
+        let mut nodes = Vec::new();
+        for (zone, rack) in [("a", "a1"), ("a", "a2"), ("b", "b1"), ("c", "c1")] {
+            for port in 12345..12350 {
+                nodes.push(ZonedNode { zone, rack, port });
+            }
+        }
+
+        let mut ch = ConsistentHash::new();
+        for node in nodes.iter() {
+            ch.add(node, 20);
+        }
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+
+            let by_zone = ch.get_str_n_across_domains(&s, 3, 0);
+            assert_eq!(by_zone.len(), 3);
+            assert_eq!(Some(by_zone[0]), ch.get_str(&s));
+            let mut zones = by_zone.iter().map(|n| n.zone).collect::<Vec<_>>();
+            zones.sort();
+            assert_eq!(zones, ["a", "b", "c"]);
+
+            // Only three zones, but four racks
+            assert_eq!(ch.get_str_n_across_domains(&s, 5, 0).len(), 3);
+            let by_rack = ch.get_str_n_across_domains(&s, 5, 1);
+            assert_eq!(by_rack.len(), 4);
+
+            // Nodes without a domain at the level are all distinct
+            assert_eq!(ch.get_str_n_across_domains(&s, 3, 2), ch.get_str_n(&s, 3));
+        }
+
+        let mut ch = ConsistentHash::new();
+        for port in 12345..12350 {
+            ch.add(&ServerNode::new("localhost", port), 20);
+        }
+        assert_eq!(
+            ch.get_str_n_across_domains("hello", 3, 0),
+            ch.get_str_n("hello", 3)
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct LabeledNode {
+        name: &'static str,
+        zone: Option<&'static str>,
+    }
+
+    impl Node for LabeledNode {
+        fn name(&self) -> String {
+            self.name.to_owned()
+        }
+
+        fn failure_domain(&self) -> Vec<String> {
+            self.zone.iter().map(|zone| zone.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn node_named_like_domain() {
+        let unplaced = LabeledNode {
+            name: "a",
+            zone: None,
+        };
+        let placed = LabeledNode {
+            name: "b",
+            zone: Some("a"),
+        };
+
+        let mut ch = ConsistentHash::new();
+        ch.add(&unplaced, 20);
+        ch.add(&placed, 20);
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str_n_across_domains(&s, 2, 0).len(), 2);
+        }
+    }
+
+    #[test]
+    fn customized_hasher() {
+        use std::{
+            collections::hash_map::DefaultHasher,
+            hash::BuildHasherDefault,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        use crate::hash::BuildHasherRing;
+
+        let nodes = (12345..12350)
+            .map(|port| ServerNode::new("localhost", port))
+            .collect::<Vec<_>>();
+
+        // A closure capturing a seed
+        let seed = b"secret".to_vec();
+        let mut seeded = ConsistentHash::with_hasher(move |input: &[u8]| {
+            let mut buf = seed.clone();
+            buf.extend_from_slice(input);
+            md5::compute(&buf).to_vec()
+        });
+        let mut plain = ConsistentHash::new();
+        for node in nodes.iter() {
+            seeded.add(node, 20);
+            plain.add(node, 20);
+        }
+        assert!((0..100)
+            .map(|i| format!("{}", i))
+            .any(|s| seeded.get_str(&s) != plain.get_str(&s)));
+
+        // A hasher counting its invocations
+        struct Counting(AtomicUsize);
+
+        impl RingHasher for Counting {
+            type Position = Vec<u8>;
+
+            fn hash(&self, input: &[u8]) -> Vec<u8> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                md5::compute(input).to_vec()
+            }
+        }
+
+        let mut counting = ConsistentHash::with_hasher(Counting(AtomicUsize::new(0)));
+        for node in nodes.iter() {
+            counting.add(node, 20);
+        }
+        assert_eq!(counting.hasher().0.load(Ordering::Relaxed), 100);
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(counting.get_str(&s), plain.get_str(&s));
+        }
+        assert_eq!(counting.hasher().0.load(Ordering::Relaxed), 200);
+
+        // A BuildHasher
+        let mut build_hasher = ConsistentHash::with_hasher(BuildHasherRing(BuildHasherDefault::<
+            DefaultHasher,
+        >::default()));
+        for node in nodes.iter() {
+            build_hasher.add(node, 20);
+        }
+        assert_eq!(build_hasher.len(), 100);
+        assert_eq!(build_hasher.get_str_n("hello", 10).len(), nodes.len());
+    }
+
+    #[test]
+    fn fixed_width_positions() {
+        use crate::hash::Md5U64;
+
+        let mut ch = ConsistentHash::new();
+        let mut ch64 = ConsistentHash::with_hasher(Md5U64);
+        for port in 12345..12355 {
+            let node = ServerNode::new("localhost", port);
+            ch.add(&node, 20);
+            ch64.add(&node, 20);
+        }
+        assert_eq!(ch64.len(), ch.len());
+
+        for i in 0..1000 {
+            let s = format!("{}", i);
+            assert_eq!(ch64.get_str(&s), ch.get_str(&s));
+            assert_eq!(ch64.get_str_n(&s, 3), ch.get_str_n(&s, 3));
+        }
+
+        let vnode = ch64.iter_from_str("hello").next().unwrap();
+        assert_eq!(
+            vnode.hash,
+            &Md5U64.hash(format!("{}:{}", vnode.node.name(), vnode.replica).as_bytes())
+        );
+    }
+
+    #[test]
+    fn customized_vnode_key() {
+        use std::cell::Cell;
+
+        use crate::{hash::Md5U64, node::BinaryKey};
+
+        // Binary keys
+        let mut ch = ConsistentHash::with_hasher_and_vnode_key(Md5U64, BinaryKey);
+        let node = ServerNode::new("localhost", 12345);
+        ch.add(&node, 20);
+        let vnode = ch.iter_from_str("hello").next().unwrap();
+        let mut key = b"localhost:12345".to_vec();
+        key.extend_from_slice(&(vnode.replica as u32).to_be_bytes());
+        assert_eq!(vnode.hash, &Md5U64.hash(&key));
+
+        // A stable node ID instead of the name, counting the derived keys
+        let derived = Cell::new(0);
+        let by_port = |node: &ServerNode, replica: usize| {
+            derived.set(derived.get() + 1);
+            format!("node-{}-{}", node.port, replica).into_bytes()
+        };
+        let mut ch = ConsistentHash::with_hasher_and_vnode_key(Md5U64, by_port);
+        let mut renamed = ConsistentHash::with_hasher_and_vnode_key(Md5U64, by_port);
+        for port in 12345..12350 {
+            ch.add(&ServerNode::new("localhost", port), 20);
+            renamed.add(&ServerNode::new("127.0.0.1", port), 20);
+        }
+        assert_eq!(derived.get(), 200);
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(
+                ch.get_str(&s).unwrap().port,
+                renamed.get_str(&s).unwrap().port
+            );
+        }
+
+        // Removing doesn't derive the keys again
+        ch.remove(&ServerNode::new("localhost", 12345));
+        assert_eq!(ch.len(), 80);
+        assert_eq!(derived.get(), 200);
+    }
+
+    #[test]
+    fn membership_events() {
+        use MembershipEvent::*;
+
+        let node0 = ServerNode::new("localhost", 12345);
+        let node1 = ServerNode::new("localhost", 12346);
+
+        let mut ch = ConsistentHash::new();
+        let rx = ch.subscribe();
+
+        ch.add(&node0, 10);
+        ch.add(&node0, 10);
+        ch.add(&node0, 20);
+        ch.remove(&node1);
+        ch.remove(&node0);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                NodeAdded {
+                    node: node0.clone(),
+                    replicas: 10
+                },
+                ReplicasChanged {
+                    node: node0.clone(),
+                    old: 10,
+                    new: 20
+                },
+                NodeRemoved {
+                    node: node0.clone()
+                },
+            ]
+        );
+
+        // Rebalancing weighted nodes changes the replicas of other nodes
+        ch.set_vnode_budget(100);
+        ch.add_weighted(&node0, 1.0);
+        ch.add_weighted(&node1, 1.0);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                NodeAdded {
+                    node: node0.clone(),
+                    replicas: 100
+                },
+                ReplicasChanged {
+                    node: node0.clone(),
+                    old: 100,
+                    new: 50
+                },
+                NodeAdded {
+                    node: node1.clone(),
+                    replicas: 50
+                },
+            ]
+        );
+
+        // Dropped receivers are unsubscribed
+        let other = ch.subscribe();
+        drop(rx);
+        ch.remove(&node1);
+        assert_eq!(
+            other.try_iter().collect::<Vec<_>>(),
+            vec![
+                NodeRemoved {
+                    node: node1.clone()
+                },
+                ReplicasChanged {
+                    node: node0.clone(),
+                    old: 50,
+                    new: 100
+                },
+            ]
+        );
+        assert_eq!(ch.subscribers.len(), 1);
+
+        // Clones don't send to the subscribers
+        let mut copy = ch.clone();
+        copy.add(&node1, 10);
+        assert_eq!(other.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn epochs() {
+        let nodes = (12345..12350)
+            .map(|port| ServerNode::new("localhost", port))
+            .collect::<Vec<_>>();
+
+        let mut ch = ConsistentHash::new();
+        assert_eq!(ch.epoch(), 0);
+        assert_eq!(ch.get_str_versioned("hello"), None);
+
+        ch.add(&nodes[0], 20);
+        ch.add(&nodes[1], 20);
+        assert_eq!(ch.epoch(), 2);
+        assert_eq!(
+            ch.get_str_versioned("hello"),
+            Some((ch.get_str("hello").unwrap(), 2))
+        );
+
+        // Mutations changing nothing keep the epoch
+        ch.remove(&nodes[4]);
+        ch.set_vnode_budget(100);
+        assert!(!ch.update(|tx| {
+            tx.add(&nodes[2], 20);
+            tx.abort();
+        }));
+        assert!(ch.update(|tx| {
+            tx.add(&nodes[2], 20);
+            tx.remove(&nodes[2]);
+        }));
+        assert_eq!(ch.epoch(), 2);
+
+        // Replacing a node is a mutation, even with the same number of replicas
+        ch.add(&nodes[0], 20);
+        assert_eq!(ch.epoch(), 3);
+        assert!(ch.update(|tx| tx.add(&nodes[1], 20)));
+        assert_eq!(ch.epoch(), 4);
+
+        // A batch bumps the epoch once
+        assert!(ch.update(|tx| {
+            tx.add(&nodes[2], 20);
+            tx.add_weighted(&nodes[3], 1.0);
+            tx.remove(&nodes[0]);
+        }));
+        assert_eq!(ch.epoch(), 5);
+
+        // So does rebalancing weighted nodes
+        ch.add_weighted(&nodes[4], 1.0);
+        ch.set_vnode_budget(200);
+        ch.remove(&nodes[4]);
+        assert_eq!(ch.epoch(), 8);
+
+        ch.add_weighted(&nodes[3], 1.0);
+        assert_eq!(ch.epoch(), 9);
+
+        let stale = ch.clone();
+        ch.add(&nodes[0], 20);
+        assert!(stale.epoch() < ch.epoch());
+    }
+
+    #[test]
+    fn batch_rolled_back_on_panicking_hasher() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        fn picky_hash(data: &[u8]) -> Vec<u8> {
+            assert!(!data.starts_with(b"unhashable"), "can't hash {:?}", data);
+            default_md5_hash_fn(data)
+        }
+
+        let nodes = (12345..12350)
+            .map(|port| ServerNode::new("localhost", port))
+            .collect::<Vec<_>>();
+
+        let mut ch = ConsistentHash::with_hash(picky_hash);
+        for node in nodes[1..].iter() {
+            ch.add(node, 20);
+        }
+        ch.add_weighted(&nodes[0], 1.0);
+        let rx = ch.subscribe();
+        let owners = (0..100)
+            .map(|i| ch.get_str(&format!("{}", i)).cloned())
+            .collect::<Vec<_>>();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ch.update(|tx| {
+                tx.remove(&nodes[1]);
+                tx.add_weighted(&nodes[2], 1.0);
+                tx.add(&ServerNode::new("unhashable", 12345), 20);
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(ch.len(), 80 + DEFAULT_VNODE_BUDGET);
+        assert_eq!(ch.epoch(), 5);
+        for (i, owner) in owners.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("{}", i)), owner.as_ref());
+        }
+        assert!(rx.try_iter().next().is_none());
+
+        // The same when hashing the rebalanced weighted nodes panics
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ch.update(|tx| {
+                tx.remove(&nodes[1]);
+                tx.add_weighted(&ServerNode::new("unhashable", 12345), 1.0);
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(ch.len(), 80 + DEFAULT_VNODE_BUDGET);
+        assert_eq!(ch.epoch(), 5);
+        assert!(ch.weight(&ServerNode::new("unhashable", 12345)).is_none());
+        assert!(rx.try_iter().next().is_none());
+
+        // Still subscribed
+        ch.remove(&nodes[1]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![MembershipEvent::NodeRemoved {
+                node: nodes[1].clone()
+            }]
+        );
+    }
+
+    #[test]
+    fn batch_updates() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        use MembershipEvent::*;
+
+        let nodes = (12345..12355)
+            .map(|port| ServerNode::new("localhost", port))
+            .collect::<Vec<_>>();
+
+        let mut ch = ConsistentHash::new();
+        for node in nodes[..5].iter() {
+            ch.add(node, 20);
+        }
+        let rx = ch.subscribe();
+        let owner = ch.get_str("hello").unwrap().clone();
+
+        // Aborted and panicking batches leave the ring untouched
+        assert!(!ch.update(|tx| {
+            tx.remove(&owner);
+            tx.abort();
+        }));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ch.update(|tx| {
+                tx.remove(&owner);
+                tx.add_weighted(&nodes[5], -1.0);
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(ch.len(), 100);
+        assert_eq!(ch.get_str("hello"), Some(&owner));
+        assert!(rx.try_iter().next().is_none());
+
+        // Subscribers get the net change of each node after the batch
+        assert!(ch.update(|tx| {
+            tx.remove(&nodes[0]);
+            tx.remove(&nodes[1]);
+            tx.add(&nodes[5], 10);
+            tx.add(&nodes[5], 20);
+            tx.add(&nodes[6], 20);
+            tx.remove(&nodes[6]);
+            tx.add(&nodes[2], 30);
+        }));
+        assert_eq!(ch.len(), 90);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                NodeRemoved {
+                    node: nodes[0].clone()
+                },
+                NodeRemoved {
+                    node: nodes[1].clone()
+                },
+                NodeAdded {
+                    node: nodes[5].clone(),
+                    replicas: 20
+                },
+                ReplicasChanged {
+                    node: nodes[2].clone(),
+                    old: 20,
+                    new: 30
+                },
+            ]
+        );
+
+        // The same ring as applying the changes one by one
+        let mut expected = ConsistentHash::new();
+        for node in nodes[2..6].iter() {
+            expected.add(node, 20);
+        }
+        expected.add(&nodes[2], 30);
+        for i in 0..100 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), expected.get_str(&s));
+        }
+
+        // Weighted nodes are rebalanced once
+        ch.set_vnode_budget(90);
+        assert!(ch.update(|tx| {
+            for node in nodes[7..].iter() {
+                tx.add_weighted(node, 1.0);
+            }
+        }));
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        for (event, node) in events.iter().zip(nodes[7..].iter()) {
+            assert_eq!(
+                event,
+                &NodeAdded {
+                    node: node.clone(),
+                    replicas: 30
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn get_n_distinct_nodes() {
+        let nodes = [
+            ServerNode::new("localhost", 12345),
+            ServerNode::new("localhost", 12346),
+            ServerNode::new("localhost", 12347),
+            ServerNode::new("localhost", 12348),
+        ];
+
+        let mut ch = ConsistentHash::new();
+        for node in nodes.iter() {
+            ch.add(node, 20);
+        }
+
+        assert!(ch.get_str_n("hello", 0).is_empty());
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            let list = ch.get_str_n(&s, 3);
+            assert_eq!(list.len(), 3);
+            assert_eq!(list[0], ch.get_str(&s).unwrap());
+            for (idx, node) in list.iter().enumerate() {
+                assert!(!list[idx + 1..].contains(node));
+            }
+
+            // Asking for more nodes than available returns every node once
+            let all = ch.get_str_n(&s, 10);
+            assert_eq!(all.len(), nodes.len());
+            assert_eq!(&all[..3], &list[..]);
+        }
+
+        assert!(ConsistentHash::<ServerNode>::new()
+            .get_str_n("hello", 3)
+            .is_empty());
+    }
 }
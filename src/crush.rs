@@ -0,0 +1,538 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Rule-based placement modeled after [CRUSH](https://ceph.io/assets/pdfs/weil-crush-sc06.pdf)
+//!
+//! Nodes are the leaves of a weighted hierarchy of buckets, for example
+//! root → zone → rack → node. A `Rule` walks the hierarchy to place the replicas of a key,
+//! like "choose 1 zone, then 3 racks in it and a node in each rack". Buckets choose their
+//! children with straw2 draws, so placement is deterministic given the hierarchy, and changing
+//! the weight of an item only moves keys to or from that item.
+
+use std::{cmp::Ordering, collections::HashSet};
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+/// Kind of the leaves of the hierarchy, use it in rules to choose nodes directly
+pub const NODE_KIND: &str = "node";
+
+// Number of draws for a replica before giving up on it
+const MAX_TRIES: u64 = 50;
+
+enum Item<N> {
+    Bucket(Bucket<N>),
+    Node { node: N, weight: f64, id: u64 },
+}
+
+impl<N: Node> Item<N> {
+    fn kind(&self) -> &str {
+        match *self {
+            Item::Bucket(ref bucket) => &bucket.kind,
+            Item::Node { .. } => NODE_KIND,
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        match *self {
+            Item::Bucket(ref bucket) => bucket.weight,
+            Item::Node { weight, .. } => weight,
+        }
+    }
+
+    fn id(&self) -> u64 {
+        match *self {
+            Item::Bucket(ref bucket) => bucket.id,
+            Item::Node { id, .. } => id,
+        }
+    }
+}
+
+/// A weighted bucket of the hierarchy, holding other buckets or nodes
+pub struct Bucket<N> {
+    name: String,
+    kind: String,
+    items: Vec<Item<N>>,
+    weight: f64,
+    id: u64,
+}
+
+impl<N: Node> Bucket<N> {
+    /// Construct an empty bucket named `name` of `kind`, like `"zone"` or `"rack"`
+    pub fn new(name: &str, kind: &str) -> Bucket<N> {
+        assert!(kind != NODE_KIND, "{:?} is the kind of nodes", NODE_KIND);
+
+        Bucket {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            items: Vec::new(),
+            weight: 0.0,
+            id: 0,
+        }
+    }
+
+    /// Add a child bucket. Its weight is the sum of the weights inside it
+    pub fn add_bucket(&mut self, bucket: Bucket<N>) {
+        self.weight += bucket.weight;
+        self.items.push(Item::Bucket(bucket));
+    }
+
+    /// Add a node with `weight`
+    pub fn add_node(&mut self, node: &N, weight: f64) {
+        assert!(
+            weight.is_finite() && weight >= 0.0,
+            "weight must be a non-negative number, but got {}",
+            weight
+        );
+
+        self.weight += weight;
+        self.items.push(Item::Node {
+            node: node.clone(),
+            weight,
+            id: 0,
+        });
+    }
+
+    /// Add a child bucket, builder style
+    pub fn with_bucket(mut self, bucket: Bucket<N>) -> Bucket<N> {
+        self.add_bucket(bucket);
+        self
+    }
+
+    /// Add a node with `weight`, builder style
+    pub fn with_node(mut self, node: &N, weight: f64) -> Bucket<N> {
+        self.add_node(node, weight);
+        self
+    }
+
+    /// Name of the bucket
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Kind of the bucket
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Sum of the weights of all nodes inside
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn assign_ids<H: RingHasher<Position = u64>>(&mut self, hasher: &H) {
+        self.id = hasher.hash(self.name.as_bytes());
+        for item in self.items.iter_mut() {
+            match *item {
+                Item::Bucket(ref mut bucket) => bucket.assign_ids(hasher),
+                Item::Node {
+                    ref node,
+                    ref mut id,
+                    ..
+                } => *id = hasher.hash(node.name().as_bytes()),
+            }
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Bucket<N>> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.items.iter().find_map(|item| match *item {
+            Item::Bucket(ref bucket) => bucket.find(name),
+            Item::Node { .. } => None,
+        })
+    }
+
+    // Straw2: every child draws a straw scaled by its weight and the longest one wins
+    fn straw2(&self, x: u64, r: u64) -> Option<&Item<N>> {
+        self.items
+            .iter()
+            .filter(|item| item.weight() > 0.0)
+            .map(|item| {
+                let h = mix64(x ^ mix64(item.id() ^ mix64(r)));
+                let u = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+                (u.ln() / item.weight(), item)
+            })
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+            .map(|(_, item)| item)
+    }
+}
+
+/// A step of a placement rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Start from the bucket with this name
+    Take(String),
+    /// Choose `count` distinct items of `kind` under each item chosen so far
+    Choose { count: usize, kind: String },
+    /// Choose `count` distinct items of `kind` under each item chosen so far,
+    /// then one node under each of them
+    ChooseLeaf { count: usize, kind: String },
+    /// Output the nodes chosen so far
+    Emit,
+}
+
+/// A placement rule, a sequence of steps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    steps: Vec<Step>,
+}
+
+impl Rule {
+    /// Construct an empty rule
+    pub fn new() -> Rule {
+        Rule { steps: Vec::new() }
+    }
+
+    /// Append a `Step::Take`
+    pub fn take(mut self, name: &str) -> Rule {
+        self.steps.push(Step::Take(name.to_owned()));
+        self
+    }
+
+    /// Append a `Step::Choose`
+    pub fn choose(mut self, count: usize, kind: &str) -> Rule {
+        self.steps.push(Step::Choose {
+            count,
+            kind: kind.to_owned(),
+        });
+        self
+    }
+
+    /// Append a `Step::ChooseLeaf`
+    pub fn choose_leaf(mut self, count: usize, kind: &str) -> Rule {
+        self.steps.push(Step::ChooseLeaf {
+            count,
+            kind: kind.to_owned(),
+        });
+        self
+    }
+
+    /// Append a `Step::Emit`
+    pub fn emit(mut self) -> Rule {
+        self.steps.push(Step::Emit);
+        self
+    }
+
+    /// Steps of the rule
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+}
+
+/// Placement over a hierarchy of buckets
+pub struct CrushMap<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    root: Bucket<N>,
+}
+
+impl<N: Node> CrushMap<N> {
+    /// Construct with default hash function (Md5)
+    #[cfg(feature = "md5")]
+    pub fn new(root: Bucket<N>) -> CrushMap<N> {
+        CrushMap::with_hash(default_md5_hash_fn, root)
+    }
+
+    /// Construct with customized hash function
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>, root: Bucket<N>) -> CrushMap<N> {
+        CrushMap::with_hasher(DigestU64(hash_fn), root)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> CrushMap<N, H> {
+    /// Construct with customized `RingHasher`
+    pub fn with_hasher(hasher: H, mut root: Bucket<N>) -> CrushMap<N, H> {
+        root.assign_ids(&hasher);
+        CrushMap { hasher, root }
+    }
+
+    /// The root bucket of the hierarchy
+    pub fn root(&self) -> &Bucket<N> {
+        &self.root
+    }
+
+    /// Place a key by `rule`. Return the emitted nodes, in the order they were chosen
+    ///
+    /// Fewer nodes are returned when the hierarchy can't satisfy the rule, for example when
+    /// it asks for more distinct zones than there are.
+    pub fn select<'a>(&'a self, rule: &Rule, key: &[u8]) -> Vec<&'a N> {
+        let x = self.hasher.hash(key);
+
+        let mut result = Vec::new();
+        let mut working: Vec<&'a Item<N>> = Vec::new();
+        let mut root_item = None;
+
+        for step in rule.steps() {
+            match *step {
+                Step::Take(ref name) => {
+                    working.clear();
+                    match self.root.find(name) {
+                        Some(bucket) => root_item = Some(bucket),
+                        None => {
+                            debug!("Bucket {:?} not exists", name);
+                            root_item = None;
+                        }
+                    }
+                }
+                Step::Choose { count, ref kind } => {
+                    working = self.choose(root_item, &working, count, kind, false, x, &result);
+                    root_item = None;
+                }
+                Step::ChooseLeaf { count, ref kind } => {
+                    working = self.choose(root_item, &working, count, kind, true, x, &result);
+                    root_item = None;
+                }
+                Step::Emit => {
+                    for item in working.drain(..) {
+                        match *item {
+                            Item::Node { ref node, .. } => result.push(node),
+                            Item::Bucket(ref bucket) => {
+                                debug!("Bucket {:?} emitted, expecting nodes", bucket.name)
+                            }
+                        }
+                    }
+                    root_item = None;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Place a string key by `rule`
+    pub fn select_str<'a>(&'a self, rule: &Rule, key: &str) -> Vec<&'a N> {
+        self.select(rule, key.as_bytes())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn choose<'a>(
+        &'a self,
+        taken: Option<&'a Bucket<N>>,
+        working: &[&'a Item<N>],
+        count: usize,
+        kind: &str,
+        leaf: bool,
+        x: u64,
+        emitted: &[&'a N],
+    ) -> Vec<&'a Item<N>> {
+        let parents = match taken {
+            Some(bucket) => vec![bucket],
+            None => working
+                .iter()
+                .filter_map(|item| match **item {
+                    Item::Bucket(ref bucket) => Some(bucket),
+                    Item::Node { .. } => None,
+                })
+                .collect(),
+        };
+
+        let mut chosen = Vec::new();
+        let mut used = emitted
+            .iter()
+            .map(|node| node.name())
+            .collect::<HashSet<_>>();
+        let mut used_ids = HashSet::new();
+        for parent in parents {
+            for rep in 0..count as u64 {
+                let mut tries = 0;
+                loop {
+                    let r = rep + tries;
+                    if let Some((item, output)) = Self::descend(parent, kind, leaf, x, r) {
+                        let duplicated = !used_ids.insert(item.id())
+                            || match *output {
+                                Item::Node { ref node, .. } => !used.insert(node.name()),
+                                Item::Bucket(_) => false,
+                            };
+                        if !duplicated {
+                            chosen.push(output);
+                            break;
+                        }
+                    }
+
+                    tries += 1;
+                    if tries >= MAX_TRIES {
+                        debug!(
+                            "No {:?} found under {:?} for replica {}",
+                            kind, parent.name, rep
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        chosen
+    }
+
+    // Descend from `bucket` to an item of `kind`, and then to a node if `leaf` is set.
+    // Return the item of `kind` and the item to output
+    fn descend<'a>(
+        bucket: &'a Bucket<N>,
+        kind: &str,
+        leaf: bool,
+        x: u64,
+        r: u64,
+    ) -> Option<(&'a Item<N>, &'a Item<N>)> {
+        let mut item = bucket.straw2(x, r)?;
+        while item.kind() != kind {
+            match *item {
+                Item::Bucket(ref bucket) => item = bucket.straw2(x, r)?,
+                Item::Node { .. } => return None,
+            }
+        }
+
+        let target = item;
+        if leaf {
+            while let Item::Bucket(ref bucket) = *item {
+                item = bucket.straw2(x, r)?;
+            }
+        }
+        Some((target, item))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::test_hash;
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct ServerNode {
+        zone: usize,
+        rack: usize,
+        host: usize,
+    }
+
+    impl Node for ServerNode {
+        fn name(&self) -> String {
+            format!("zone{}-rack{}-host{}", self.zone, self.rack, self.host)
+        }
+    }
+
+    fn hierarchy(zones: usize, racks: usize, hosts: usize) -> Bucket<ServerNode> {
+        let mut root = Bucket::new("root", "root");
+        for zone in 0..zones {
+            let mut z = Bucket::new(&format!("zone{}", zone), "zone");
+            for rack in 0..racks {
+                let mut r = Bucket::new(&format!("zone{}-rack{}", zone, rack), "rack");
+                for host in 0..hosts {
+                    r.add_node(&ServerNode { zone, rack, host }, 1.0);
+                }
+                z.add_bucket(r);
+            }
+            root.add_bucket(z);
+        }
+        root
+    }
+
+    #[test]
+    fn bucket_weights() {
+        let root = hierarchy(3, 2, 4);
+        assert_eq!(root.weight(), 24.0);
+        assert_eq!(root.kind(), "root");
+        assert_eq!(root.find("zone1").unwrap().weight(), 8.0);
+        assert!(root.find("zone3").is_none());
+    }
+
+    #[test]
+    fn one_zone_three_racks() {
+        let map = CrushMap::with_hash(test_hash, hierarchy(3, 4, 3));
+        let rule = Rule::new()
+            .take("root")
+            .choose(1, "zone")
+            .choose_leaf(3, "rack")
+            .emit();
+
+        for i in 0..200 {
+            let s = format!("{}", i);
+            let nodes = map.select_str(&rule, &s);
+            assert_eq!(nodes.len(), 3);
+            assert!(nodes.iter().all(|n| n.zone == nodes[0].zone));
+            let racks = nodes.iter().map(|n| n.rack).collect::<HashSet<_>>();
+            assert_eq!(racks.len(), 3);
+
+            // Deterministic, even for an identical map built again
+            assert_eq!(map.select_str(&rule, &s), nodes);
+            let again = CrushMap::with_hash(test_hash, hierarchy(3, 4, 3));
+            assert_eq!(again.select_str(&rule, &s), nodes);
+        }
+    }
+
+    #[test]
+    fn replicas_across_zones() {
+        let map = CrushMap::with_hash(test_hash, hierarchy(3, 2, 2));
+        let rule = Rule::new().take("root").choose_leaf(3, "zone").emit();
+
+        let mut primaries = HashSet::new();
+        for i in 0..200 {
+            let nodes = map.select_str(&rule, &format!("{}", i));
+            let zones = nodes.iter().map(|n| n.zone).collect::<HashSet<_>>();
+            assert_eq!(zones.len(), 3);
+            primaries.insert(nodes[0].name());
+        }
+        assert_eq!(primaries.len(), 12);
+
+        // Not enough zones
+        let rule = Rule::new().take("root").choose_leaf(4, "zone").emit();
+        assert_eq!(map.select_str(&rule, "hello").len(), 3);
+
+        // Nodes chosen directly
+        let rule = Rule::new().take("zone1").choose(3, NODE_KIND).emit();
+        let nodes = map.select_str(&rule, "hello");
+        assert_eq!(nodes.len(), 3);
+        assert!(nodes.iter().all(|n| n.zone == 1));
+
+        // Unknown bucket
+        let rule = Rule::new().take("zone9").choose(1, NODE_KIND).emit();
+        assert!(map.select_str(&rule, "hello").is_empty());
+    }
+
+    #[test]
+    fn weights_are_respected() {
+        let light = ServerNode {
+            zone: 0,
+            rack: 0,
+            host: 0,
+        };
+        let heavy = ServerNode {
+            zone: 0,
+            rack: 0,
+            host: 1,
+        };
+        let drained = ServerNode {
+            zone: 0,
+            rack: 0,
+            host: 2,
+        };
+        let root = Bucket::new("root", "root")
+            .with_node(&light, 1.0)
+            .with_node(&heavy, 3.0)
+            .with_node(&drained, 0.0);
+        let map = CrushMap::with_hash(test_hash, root);
+        let rule = Rule::new().take("root").choose(1, NODE_KIND).emit();
+
+        const KEYS: usize = 10000;
+        let mut heavy_keys = 0;
+        for i in 0..KEYS {
+            let nodes = map.select_str(&rule, &format!("{}", i));
+            assert_eq!(nodes.len(), 1);
+            assert_ne!(nodes[0], &drained);
+            if nodes[0] == &heavy {
+                heavy_keys += 1;
+            }
+        }
+
+        let share = heavy_keys as f64 / KEYS as f64;
+        assert!(share > 0.72 && share < 0.78, "share {}", share);
+    }
+}
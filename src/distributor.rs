@@ -0,0 +1,412 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Common interface of the key distribution algorithms in this crate
+//!
+//! `BoundedLoadConsistentHash` doesn't implement `KeyDistributor`: the node of a key depends on
+//! the loads reported with `acquire` and `release`, and there is no preference list of nodes.
+
+use crate::{
+    hash::RingHasher, node::VnodeKey, AnchorHash, ConsistentHash, JumpHash, Maglev, MultiProbe,
+    Node, Rendezvous,
+};
+
+#[cfg(feature = "xxhash")]
+use crate::compat::Envoy;
+#[cfg(feature = "md5")]
+use crate::compat::Ketama;
+#[cfg(feature = "crc32")]
+use crate::compat::{Groupcache, Nginx};
+
+/// Distributes keys over a set of nodes
+pub trait KeyDistributor<N: Node> {
+    /// Add a new node, or update an existing node with the same name
+    ///
+    /// Nodes added here all have the same weight: for `ConsistentHash` they share the virtual
+    /// node budget equally, see `ConsistentHash::add_weighted`. Return `false` if the node
+    /// could not be added, which happens when all buckets of an `AnchorHash` are in use.
+    fn add(&mut self, node: &N) -> bool;
+
+    /// Remove a node
+    fn remove(&mut self, node: &N);
+
+    /// Get a node by key. Return `None` if no valid node inside
+    fn get(&self, key: &[u8]) -> Option<&N>;
+
+    /// Get up to `n` distinct nodes by key, from the most preferred to the least
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N>;
+
+    /// Number of nodes, without the virtual nodes of ring-based algorithms
+    fn len(&self) -> usize;
+
+    /// Is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get a node by string key
+    fn get_str(&self, key: &str) -> Option<&N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    fn get_str_n(&self, key: &str, n: usize) -> Vec<&N> {
+        self.get_n(key.as_bytes(), n)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> KeyDistributor<N> for AnchorHash<N, H> {
+    fn add(&mut self, node: &N) -> bool {
+        AnchorHash::add(self, node)
+    }
+
+    fn remove(&mut self, node: &N) {
+        AnchorHash::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        AnchorHash::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        AnchorHash::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        AnchorHash::len(self)
+    }
+}
+
+impl<N: Node, H: RingHasher, K: VnodeKey<N>> KeyDistributor<N> for ConsistentHash<N, H, K> {
+    fn add(&mut self, node: &N) -> bool {
+        ConsistentHash::add_weighted(self, node, 1.0);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        ConsistentHash::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        ConsistentHash::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        ConsistentHash::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        ConsistentHash::num_nodes(self)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> KeyDistributor<N> for JumpHash<N, H> {
+    fn add(&mut self, node: &N) -> bool {
+        JumpHash::add(self, node);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        JumpHash::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        JumpHash::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        JumpHash::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        JumpHash::len(self)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> KeyDistributor<N> for Rendezvous<N, H> {
+    fn add(&mut self, node: &N) -> bool {
+        Rendezvous::add(self, node, 1.0);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Rendezvous::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Rendezvous::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Rendezvous::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Rendezvous::len(self)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> KeyDistributor<N> for Maglev<N, H> {
+    fn add(&mut self, node: &N) -> bool {
+        Maglev::add(self, node);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Maglev::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Maglev::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Maglev::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Maglev::len(self)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> KeyDistributor<N> for MultiProbe<N, H> {
+    fn add(&mut self, node: &N) -> bool {
+        MultiProbe::add(self, node);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        MultiProbe::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        MultiProbe::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        MultiProbe::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        MultiProbe::len(self)
+    }
+}
+
+#[cfg(feature = "xxhash")]
+impl<N: Node> KeyDistributor<N> for Envoy<N> {
+    fn add(&mut self, node: &N) -> bool {
+        Envoy::add(self, node, 1);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Envoy::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Envoy::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Envoy::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Envoy::len(self)
+    }
+}
+
+#[cfg(feature = "crc32")]
+impl<N: Node> KeyDistributor<N> for Groupcache<N> {
+    fn add(&mut self, node: &N) -> bool {
+        Groupcache::add(self, node);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Groupcache::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Groupcache::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Groupcache::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Groupcache::len(self)
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> KeyDistributor<N> for Ketama<N> {
+    fn add(&mut self, node: &N) -> bool {
+        Ketama::add(self, node, 1);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Ketama::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Ketama::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Ketama::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Ketama::len(self)
+    }
+}
+
+#[cfg(feature = "crc32")]
+impl<N: Node> KeyDistributor<N> for Nginx<N> {
+    fn add(&mut self, node: &N) -> bool {
+        Nginx::add(self, node, 1);
+        true
+    }
+
+    fn remove(&mut self, node: &N) {
+        Nginx::remove(self, node)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&N> {
+        Nginx::get(self, key)
+    }
+
+    fn get_n(&self, key: &[u8], n: usize) -> Vec<&N> {
+        Nginx::get_n(self, key, n)
+    }
+
+    fn len(&self) -> usize {
+        Nginx::len(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    fn check_distributor<D: KeyDistributor<ServerNode>>(mut distributor: D) {
+        assert!(distributor.is_empty());
+        assert_eq!(distributor.get_str("hello"), None);
+        assert!(distributor.get_str_n("hello", 3).is_empty());
+
+        let nodes = local_nodes(5);
+        for node in nodes.iter() {
+            assert!(distributor.add(node));
+        }
+        assert!(!distributor.is_empty());
+        assert_eq!(distributor.len(), nodes.len());
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            let list = distributor.get_str_n(&s, 3);
+            assert_eq!(list.len(), 3);
+            assert_eq!(Some(list[0]), distributor.get_str(&s));
+            for (idx, node) in list.iter().enumerate() {
+                assert!(!list[idx + 1..].contains(node));
+            }
+            assert_eq!(distributor.get_str_n(&s, 10).len(), nodes.len());
+        }
+
+        for node in nodes.iter() {
+            distributor.remove(node);
+        }
+        assert!(distributor.is_empty());
+        assert_eq!(distributor.get_str("hello"), None);
+    }
+
+    // Keys of one primary node have their secondaries spread over the other nodes, so its load
+    // is shared when it fails
+    fn check_secondaries<D: KeyDistributor<ServerNode>>(mut distributor: D) {
+        let nodes = local_nodes(10);
+        for node in nodes.iter() {
+            assert!(distributor.add(node));
+        }
+
+        let mut secondaries = HashMap::new();
+        for i in 0..5000 {
+            let list = distributor.get_str_n(&format!("{}", i), 2);
+            let (keys, names) = secondaries
+                .entry(list[0].name())
+                .or_insert_with(|| (0, HashSet::new()));
+            *keys += 1;
+            names.insert(list[1].name());
+        }
+        for (primary, (keys, names)) in secondaries.iter() {
+            // A node with a short arc may own just a few keys
+            if *keys >= 100 {
+                assert!(
+                    names.len() >= nodes.len() / 2,
+                    "{:?} has secondaries {:?}",
+                    primary,
+                    names
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn all_distributors() {
+        check_distributor(AnchorHash::with_hash(test_hash, 16));
+        check_distributor(ConsistentHash::with_hash(test_hash));
+        check_distributor(JumpHash::with_hash(test_hash));
+        check_distributor(Rendezvous::with_hash(test_hash));
+        check_distributor(Maglev::with_hash_and_table_size(test_hash, 251));
+        check_distributor(MultiProbe::with_hash(test_hash));
+        #[cfg(feature = "md5")]
+        check_distributor(Ketama::new());
+        #[cfg(feature = "xxhash")]
+        check_distributor(Envoy::new());
+        #[cfg(feature = "crc32")]
+        check_distributor(Groupcache::new(50));
+        #[cfg(feature = "crc32")]
+        check_distributor(Nginx::new());
+    }
+
+    #[test]
+    fn custom_hasher() {
+        use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault};
+
+        use crate::hash::BuildHasherRing;
+
+        let hasher = BuildHasherRing(BuildHasherDefault::<DefaultHasher>::default());
+        check_distributor(AnchorHash::with_hasher(hasher.clone(), 16));
+        check_distributor(JumpHash::with_hasher(hasher.clone()));
+        check_distributor(Rendezvous::with_hasher(hasher.clone()));
+        check_distributor(Maglev::with_hasher_and_table_size(hasher.clone(), 251));
+        check_distributor(MultiProbe::with_hasher(hasher));
+    }
+
+    #[test]
+    fn spread_secondaries() {
+        check_secondaries(AnchorHash::with_hash(test_hash, 16));
+        check_secondaries(ConsistentHash::with_hash(test_hash));
+        check_secondaries(JumpHash::with_hash(test_hash));
+        check_secondaries(Rendezvous::with_hash(test_hash));
+        check_secondaries(Maglev::with_hash_and_table_size(test_hash, 251));
+        check_secondaries(MultiProbe::with_hash(test_hash));
+        #[cfg(feature = "md5")]
+        check_secondaries(Ketama::new());
+        #[cfg(feature = "xxhash")]
+        check_secondaries(Envoy::new());
+        #[cfg(feature = "crc32")]
+        check_secondaries(Groupcache::new(50));
+        #[cfg(feature = "crc32")]
+        check_secondaries(Nginx::new());
+    }
+}
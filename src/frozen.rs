@@ -0,0 +1,340 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An immutable ring, built once and optimized for reads
+//!
+//! `ConsistentHashBuilder` places nodes exactly like `ConsistentHash` does, then `build()`
+//! freezes the ring into sorted arrays. Lookups are a binary search over contiguous positions,
+//! and clones share the same arrays.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    conhash::VirtualNode,
+    hash::RingHasher,
+    node::{NameReplicaKey, VnodeKey},
+    ConsistentHash, Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+struct Ring<N, H: RingHasher> {
+    hasher: H,
+    // Sorted positions of the virtual nodes
+    positions: Vec<H::Position>,
+    // Replica index and node index of the virtual node at the same index in `positions`
+    vnodes: Vec<(usize, usize)>,
+    nodes: Vec<N>,
+}
+
+/// Immutable Consistent Hash, created by `ConsistentHashBuilder`
+///
+/// Cloning is cheap, the ring is shared.
+pub struct FrozenConsistentHash<N: Node, H: RingHasher = fn(&[u8]) -> Vec<u8>> {
+    ring: Arc<Ring<N, H>>,
+}
+
+impl<N: Node, H: RingHasher> Clone for FrozenConsistentHash<N, H> {
+    fn clone(&self) -> Self {
+        FrozenConsistentHash {
+            ring: self.ring.clone(),
+        }
+    }
+}
+
+impl<N: Node, H: RingHasher> FrozenConsistentHash<N, H> {
+    pub(crate) fn new(
+        hasher: H,
+        vnodes: BTreeMap<H::Position, (usize, N)>,
+    ) -> FrozenConsistentHash<N, H> {
+        let mut ring = Ring {
+            hasher,
+            positions: Vec::with_capacity(vnodes.len()),
+            vnodes: Vec::with_capacity(vnodes.len()),
+            nodes: Vec::new(),
+        };
+
+        let mut indices = HashMap::new();
+        for (position, (replica, node)) in vnodes {
+            let idx = *indices.entry(node.name()).or_insert_with(|| {
+                ring.nodes.push(node);
+                ring.nodes.len() - 1
+            });
+            ring.positions.push(position);
+            ring.vnodes.push((replica, idx));
+        }
+        debug!(
+            "Frozen ring with {} virtual nodes of {} nodes",
+            ring.positions.len(),
+            ring.nodes.len()
+        );
+
+        FrozenConsistentHash {
+            ring: Arc::new(ring),
+        }
+    }
+
+    /// The hasher placing keys and virtual nodes
+    pub fn hasher(&self) -> &H {
+        &self.ring.hasher
+    }
+
+    // Indices of the virtual nodes clockwise, starting from the successor of the key's position
+    fn indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let positions = &self.ring.positions;
+        let hashed_key = self.ring.hasher.hash(key);
+        debug!("Walking from key {:?}, hashed key is {:?}", key, hashed_key);
+
+        let start = positions.partition_point(|p| p < &hashed_key);
+        (start..positions.len()).chain(0..start)
+    }
+
+    /// Iterate virtual nodes clockwise, starting from the successor of the key's position
+    ///
+    /// The iterator wraps around the ring once, so every virtual node is visited exactly once.
+    pub fn iter_from<'a>(
+        &'a self,
+        key: &[u8],
+    ) -> impl Iterator<Item = VirtualNode<'a, N, H::Position>> + 'a {
+        let ring = &*self.ring;
+        self.indices(key).map(move |idx| VirtualNode {
+            hash: &ring.positions[idx],
+            replica: ring.vnodes[idx].0,
+            node: &ring.nodes[ring.vnodes[idx].1],
+        })
+    }
+
+    /// Iterate virtual nodes clockwise, starting from the successor of the string key's position
+    pub fn iter_from_str<'a>(
+        &'a self,
+        key: &str,
+    ) -> impl Iterator<Item = VirtualNode<'a, N, H::Position>> + 'a {
+        self.iter_from(key.as_bytes())
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        match self.indices(key).next() {
+            Some(idx) => {
+                let node = &self.ring.nodes[self.ring.vnodes[idx].1];
+                debug!("Found node {:?}", node.name());
+                Some(node)
+            }
+            None => {
+                debug!("The container is empty");
+                None
+            }
+        }
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get the first node by key that satisfies `predicate`, continuing clockwise past the ones
+    /// that don't. Return `None` if no valid node inside
+    pub fn get_with<'a, P>(&'a self, key: &[u8], mut predicate: P) -> Option<&'a N>
+    where
+        P: FnMut(&N) -> bool,
+    {
+        self.iter_from(key)
+            .map(|vnode| vnode.node)
+            .find(|node| predicate(node))
+    }
+
+    /// Get the first node by string key that satisfies `predicate`
+    pub fn get_str_with<'a, P>(&'a self, key: &str, predicate: P) -> Option<&'a N>
+    where
+        P: FnMut(&N) -> bool,
+    {
+        self.get_with(key.as_bytes(), predicate)
+    }
+
+    /// Get up to `n` distinct nodes by key, in clockwise order starting from the key's position
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let mut seen = HashSet::with_capacity(n);
+        self.indices(key)
+            .map(|idx| self.ring.vnodes[idx].1)
+            .filter(|&node| seen.insert(node))
+            .take(n.min(self.ring.nodes.len()))
+            .map(|node| &self.ring.nodes[node])
+            .collect()
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of nodes
+    pub fn num_nodes(&self) -> usize {
+        self.ring.nodes.len()
+    }
+
+    /// Number of virtual nodes
+    pub fn len(&self) -> usize {
+        self.ring.positions.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Builder of `FrozenConsistentHash`
+pub struct ConsistentHashBuilder<
+    N: Node,
+    H: RingHasher = fn(&[u8]) -> Vec<u8>,
+    K: VnodeKey<N> = NameReplicaKey,
+> {
+    ring: ConsistentHash<N, H, K>,
+}
+
+impl<N: Node> ConsistentHashBuilder<N> {
+    /// Construct with default hash function (Md5)
+    #[cfg(feature = "md5")]
+    pub fn new() -> ConsistentHashBuilder<N> {
+        ConsistentHashBuilder::with_hash(default_md5_hash_fn)
+    }
+
+    /// Construct with customized hash function
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> ConsistentHashBuilder<N> {
+        ConsistentHashBuilder::with_hasher(hash_fn)
+    }
+}
+
+impl<N: Node, H: RingHasher> ConsistentHashBuilder<N, H> {
+    /// Construct with customized `RingHasher`
+    pub fn with_hasher(hasher: H) -> ConsistentHashBuilder<N, H> {
+        ConsistentHashBuilder::with_hasher_and_vnode_key(hasher, NameReplicaKey)
+    }
+}
+
+impl<N: Node, H: RingHasher, K: VnodeKey<N>> ConsistentHashBuilder<N, H, K> {
+    /// Construct with customized `RingHasher` and derivation of virtual node keys
+    pub fn with_hasher_and_vnode_key(hasher: H, vnode_key: K) -> ConsistentHashBuilder<N, H, K> {
+        ConsistentHashBuilder {
+            ring: ConsistentHash::with_hasher_and_vnode_key(hasher, vnode_key),
+        }
+    }
+
+    /// Add a node with a fixed number of replicas, see `ConsistentHash::add`
+    pub fn node(mut self, node: &N, num_replicas: usize) -> Self {
+        self.ring.add(node, num_replicas);
+        self
+    }
+
+    /// Add a node with a relative `weight`, see `ConsistentHash::add_weighted`
+    pub fn weighted_node(mut self, node: &N, weight: f64) -> Self {
+        self.ring.add_weighted(node, weight);
+        self
+    }
+
+    /// Set the number of virtual nodes shared by the weighted nodes
+    pub fn vnode_budget(mut self, vnode_budget: usize) -> Self {
+        self.ring.set_vnode_budget(vnode_budget);
+        self
+    }
+
+    /// Build the immutable ring
+    pub fn build(self) -> FrozenConsistentHash<N, H> {
+        self.ring.freeze()
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for ConsistentHashBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    #[test]
+    fn same_placement_as_mutable_ring() {
+        let mut builder = ConsistentHashBuilder::with_hash(test_hash).vnode_budget(200);
+        let mut ring = ConsistentHash::with_hash(test_hash);
+        ring.set_vnode_budget(200);
+        for node in local_nodes(5).iter() {
+            builder = builder.node(node, 20);
+            ring.add(node, 20);
+        }
+        for port in 12350..12353 {
+            let node = ServerNode::new("localhost", port);
+            builder = builder.weighted_node(&node, port as f64);
+            ring.add_weighted(&node, port as f64);
+        }
+
+        let ch = builder.build();
+        assert_eq!(ch.len(), ring.len());
+        assert_eq!(ch.num_nodes(), 8);
+
+        for i in 0..1000 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), ring.get_str(&s));
+            assert_eq!(ch.get_str_n(&s, 3), ring.get_str_n(&s, 3));
+            assert_eq!(
+                ch.get_str_with(&s, |node| node.name() != "localhost:12346"),
+                ring.get_str_with(&s, |node| node.name() != "localhost:12346")
+            );
+        }
+
+        let frozen = ch
+            .iter_from_str("hello")
+            .map(|vnode| (vnode.hash.clone(), vnode.replica, vnode.node.clone()))
+            .collect::<Vec<_>>();
+        let mutable = ring
+            .iter_from_str("hello")
+            .map(|vnode| (vnode.hash.clone(), vnode.replica, vnode.node.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(frozen, mutable);
+        assert_eq!(ch.get_str_n("hello", 20).len(), 8);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        use std::thread;
+
+        use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault};
+
+        use crate::{hash::BuildHasherRing, node::BinaryKey};
+
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut builder = ConsistentHashBuilder::with_hasher_and_vnode_key(
+            BuildHasherRing(BuildHasherDefault::<DefaultHasher>::default()),
+            BinaryKey,
+        );
+        for node in local_nodes(5).iter() {
+            builder = builder.node(node, 20);
+        }
+        let ch = builder.build();
+        assert_send_sync(&ch);
+
+        let expected = ch.get_str("hello").cloned();
+        let handles = (0..4)
+            .map(|_| {
+                let ch = ch.clone();
+                thread::spawn(move || ch.get_str("hello").cloned())
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hash functions placing keys and nodes
+
+use std::{
+    fmt::Debug,
+    hash::{BuildHasher, Hasher},
+};
+
+/// Hash function placing keys and virtual nodes on a ring
+///
+/// Implemented for every `Fn(&[u8]) -> Vec<u8>`, including plain function pointers, so
+/// closures capturing a seed or a key can be used as well. Hashers with a fixed-width
+/// `Position`, like the ones in this module, make lookups allocation free.
+pub trait RingHasher {
+    /// Position on the ring
+    type Position: Ord + Clone + Debug;
+
+    /// Hash `input` into a position
+    fn hash(&self, input: &[u8]) -> Self::Position;
+}
+
+impl<F> RingHasher for F
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    type Position = Vec<u8>;
+
+    fn hash(&self, input: &[u8]) -> Vec<u8> {
+        self(input)
+    }
+}
+
+/// Use a `BuildHasher` as a `RingHasher`
+///
+/// A `BuildHasher` seeded randomly per process, like `std::collections::hash_map::RandomState`,
+/// places the nodes differently in every process.
+#[derive(Debug, Clone, Default)]
+pub struct BuildHasherRing<B>(pub B);
+
+impl<B: BuildHasher> RingHasher for BuildHasherRing<B> {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        let mut hasher = self.0.build_hasher();
+        hasher.write(input);
+        hasher.finish()
+    }
+}
+
+/// A hash function with `u64` positions, the first 8 bytes of its digest in big-endian
+///
+/// The hasher of the algorithms built by `with_hash`, which place keys by `u64` hashes.
+#[derive(Debug, Clone, Copy)]
+pub struct DigestU64(pub fn(&[u8]) -> Vec<u8>);
+
+impl RingHasher for DigestU64 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        digest_to_u64(&(self.0)(input))
+    }
+}
+
+/// Md5 with `u64` positions, the first 8 bytes of the digest in big-endian
+///
+/// Places keys and nodes in the same order as the default Md5 hash function, except for
+/// digests sharing the first 8 bytes.
+#[cfg(feature = "md5")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Md5U64;
+
+#[cfg(feature = "md5")]
+impl RingHasher for Md5U64 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        digest_to_u64(&md5::compute(input)[..])
+    }
+}
+
+/// XXH3 64-bit with a seed
+#[cfg(feature = "xxhash")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xxh3 {
+    pub seed: u64,
+}
+
+#[cfg(feature = "xxhash")]
+impl RingHasher for Xxh3 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        xxhash_rust::xxh3::xxh3_64_with_seed(input, self.seed)
+    }
+}
+
+/// MurmurHash3 x64 128-bit with a seed
+#[cfg(feature = "murmur3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Murmur3 {
+    pub seed: u32,
+}
+
+#[cfg(feature = "murmur3")]
+impl RingHasher for Murmur3 {
+    type Position = u128;
+
+    fn hash(&self, mut input: &[u8]) -> u128 {
+        murmur3::murmur3_x64_128(&mut input, self.seed).expect("reading from a slice never fails")
+    }
+}
+
+/// FNV-1a 64-bit
+#[cfg(feature = "fnv")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fnv1a;
+
+#[cfg(feature = "fnv")]
+impl RingHasher for Fnv1a {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        input
+            .iter()
+            .fold(OFFSET_BASIS, |h, &b| (h ^ b as u64).wrapping_mul(PRIME))
+    }
+}
+
+/// CRC-32 (IEEE)
+#[cfg(feature = "crc32")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32;
+
+#[cfg(feature = "crc32")]
+impl RingHasher for Crc32 {
+    type Position = u32;
+
+    fn hash(&self, input: &[u8]) -> u32 {
+        crc32fast::hash(input)
+    }
+}
+
+/// SipHash-1-3 with a 128-bit secret key
+#[cfg(feature = "siphash")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SipHash13 {
+    pub key: [u8; 16],
+}
+
+#[cfg(feature = "siphash")]
+impl RingHasher for SipHash13 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        let mut hasher = siphasher::sip::SipHasher13::new_with_key(&self.key);
+        hasher.write(input);
+        hasher.finish()
+    }
+}
+
+/// BLAKE3, the first 8 bytes of the digest in big-endian
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl RingHasher for Blake3 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        digest_to_u64(blake3::hash(input).as_bytes())
+    }
+}
+
+/// SHA-1, the first 8 bytes of the digest in big-endian
+#[cfg(feature = "sha1")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha1;
+
+#[cfg(feature = "sha1")]
+impl RingHasher for Sha1 {
+    type Position = u64;
+
+    fn hash(&self, input: &[u8]) -> u64 {
+        digest_to_u64(&sha1_smol::Sha1::from(input).digest().bytes())
+    }
+}
+
+#[cfg(feature = "md5")]
+pub(crate) fn default_md5_hash_fn(input: &[u8]) -> Vec<u8> {
+    let digest = md5::compute(input);
+    digest.to_vec()
+}
+
+// Take the first 8 bytes of a digest as a big-endian integer, zero padded if it is shorter
+pub(crate) fn digest_to_u64(digest: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let len = digest.len().min(buf.len());
+    buf[..len].copy_from_slice(&digest[..len]);
+    u64::from_be_bytes(buf)
+}
+
+// Finalizer of SplitMix64, spreads the bits of `x` into a uniformly distributed value
+pub(crate) fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    // Every test depends on a feature
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn md5() {
+        assert_eq!(Md5U64.hash(b""), 0xd41d8cd98f00b204);
+        assert_eq!(DigestU64(default_md5_hash_fn).hash(b""), 0xd41d8cd98f00b204);
+        assert_eq!(
+            default_md5_hash_fn(b"")[..8],
+            0xd41d8cd98f00b204u64.to_be_bytes()
+        );
+    }
+
+    #[cfg(feature = "xxhash")]
+    #[test]
+    fn xxh3() {
+        assert_eq!(Xxh3 { seed: 0 }.hash(b""), 0x2d06800538d394c2);
+        assert_ne!(
+            Xxh3 { seed: 1 }.hash(b"hello"),
+            Xxh3 { seed: 0 }.hash(b"hello")
+        );
+    }
+
+    #[cfg(feature = "murmur3")]
+    #[test]
+    fn murmur3() {
+        assert_eq!(Murmur3 { seed: 0 }.hash(b""), 0);
+        assert_ne!(
+            Murmur3 { seed: 1 }.hash(b"hello"),
+            Murmur3 { seed: 0 }.hash(b"hello")
+        );
+    }
+
+    #[cfg(feature = "fnv")]
+    #[test]
+    fn fnv1a() {
+        assert_eq!(Fnv1a.hash(b""), 0xcbf29ce484222325);
+        assert_eq!(Fnv1a.hash(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[cfg(feature = "crc32")]
+    #[test]
+    fn crc32() {
+        assert_eq!(Crc32.hash(b"123456789"), 0xcbf43926);
+    }
+
+    #[cfg(feature = "siphash")]
+    #[test]
+    fn siphash13() {
+        let key = [7u8; 16];
+        assert_eq!(
+            SipHash13 { key }.hash(b"hello"),
+            SipHash13 { key }.hash(b"hello")
+        );
+        assert_ne!(
+            SipHash13 { key }.hash(b"hello"),
+            SipHash13::default().hash(b"hello")
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3() {
+        assert_eq!(Blake3.hash(b""), 0xaf1349b9f5f9a1a6);
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn sha1() {
+        assert_eq!(Sha1.hash(b"abc"), 0xa9993e364706816a);
+    }
+}
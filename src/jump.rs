@@ -0,0 +1,273 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Jump Consistent Hash](https://arxiv.org/abs/1406.2294)
+//!
+//! Nodes are numbered buckets. No memory is used per virtual node, but nodes can only be
+//! appended to or removed from the end without remapping keys of the other nodes. Removing
+//! any other node also remaps the keys of the last bucket.
+
+use std::collections::HashMap;
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+fn jump_consistent_hash(mut key: u64, num_buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+/// Jump Consistent Hash
+pub struct JumpHash<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    nodes: Vec<N>,
+    buckets: HashMap<String, usize>,
+}
+
+impl<N: Node> JumpHash<N> {
+    /// Construct with default hash function (Md5)
+    #[cfg(feature = "md5")]
+    pub fn new() -> JumpHash<N> {
+        JumpHash::with_hash(default_md5_hash_fn)
+    }
+
+    /// Construct with customized hash function
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> JumpHash<N> {
+        JumpHash::with_hasher(DigestU64(hash_fn))
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> JumpHash<N, H> {
+    /// Construct with customized `RingHasher`
+    pub fn with_hasher(hasher: H) -> JumpHash<N, H> {
+        JumpHash {
+            hasher,
+            nodes: Vec::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Append a new node as the last bucket.
+    /// A node with the same name is replaced in its current bucket
+    pub fn add(&mut self, node: &N) {
+        let node_name = node.name();
+        match self.buckets.get(&node_name) {
+            Some(&bucket) => {
+                debug!("Replacing node {:?} in bucket {}", node_name, bucket);
+                self.nodes[bucket] = node.clone();
+            }
+            None => {
+                debug!("Adding node {:?} to bucket {}", node_name, self.nodes.len());
+                self.buckets.insert(node_name, self.nodes.len());
+                self.nodes.push(node.clone());
+            }
+        }
+    }
+
+    /// Remove the node in the last bucket
+    pub fn pop(&mut self) -> Option<N> {
+        let node = self.nodes.pop()?;
+        debug!("Removing node {:?}", node.name());
+        self.buckets.remove(&node.name());
+        Some(node)
+    }
+
+    /// Remove a node. The node in the last bucket is moved into the freed bucket, so only the
+    /// keys of the removed node and of the last bucket are remapped
+    pub fn remove(&mut self, node: &N) {
+        let node_name = node.name();
+        let bucket = match self.buckets.get(&node_name) {
+            Some(&bucket) => bucket,
+            None => {
+                debug!("Node {:?} not exists", node_name);
+                return;
+            }
+        };
+
+        debug!("Removing node {:?} from bucket {}", node_name, bucket);
+        self.nodes.swap_remove(bucket);
+        self.buckets.remove(&node_name);
+        if let Some(moved) = self.nodes.get(bucket) {
+            debug!("Moving node {:?} to bucket {}", moved.name(), bucket);
+            self.buckets.insert(moved.name(), bucket);
+        }
+    }
+
+    /// Get the bucket number by key. Return `None` if no valid node inside
+    pub fn bucket(&self, key: &[u8]) -> Option<usize> {
+        if self.nodes.is_empty() {
+            debug!("The container is empty");
+            return None;
+        }
+
+        let bucket = jump_consistent_hash(self.hash_key(key), self.nodes.len());
+        debug!("Getting key {:?}, bucket is {}", key, bucket);
+        Some(bucket)
+    }
+
+    fn hash_key(&self, key: &[u8]) -> u64 {
+        self.hasher.hash(key)
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        self.bucket(key).map(|bucket| &self.nodes[bucket])
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key, the key's bucket first
+    ///
+    /// The other buckets are jumped to by the key re-hashed with the replica number, skipping
+    /// buckets already taken, so the replicas of a key are spread like the keys themselves.
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        if self.nodes.is_empty() {
+            debug!("The container is empty");
+            return Vec::new();
+        }
+
+        let hashed_key = self.hash_key(key);
+        let mut taken = vec![false; self.nodes.len()];
+        let want = n.min(self.nodes.len());
+        let mut res = Vec::with_capacity(want);
+        let mut replica = 0;
+        while res.len() < want {
+            let seeded = if replica == 0 {
+                hashed_key
+            } else {
+                mix64(hashed_key ^ mix64(replica))
+            };
+            let bucket = jump_consistent_hash(seeded, self.nodes.len());
+            if !taken[bucket] {
+                taken[bucket] = true;
+                res.push(&self.nodes[bucket]);
+            }
+            replica += 1;
+        }
+        res
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for JumpHash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    #[test]
+    fn reference_buckets() {
+        // Test vectors shared by other implementations of the algorithm
+        assert_eq!(jump_consistent_hash(1, 1), 0);
+        assert_eq!(jump_consistent_hash(42, 57), 43);
+        assert_eq!(jump_consistent_hash(0xDEAD10CC, 1), 0);
+        assert_eq!(jump_consistent_hash(0xDEAD10CC, 666), 361);
+        assert_eq!(jump_consistent_hash(256, 1024), 520);
+    }
+
+    #[test]
+    fn add_and_pop() {
+        let mut ch = JumpHash::with_hash(test_hash);
+        for node in local_nodes(10).iter() {
+            ch.add(node);
+        }
+        assert_eq!(ch.len(), 10);
+
+        // Adding the same node again doesn't change the buckets
+        ch.add(&ServerNode::new("localhost", 12345));
+        assert_eq!(ch.len(), 10);
+
+        let before = (0..1000)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        let removed = ch.pop().unwrap();
+        assert_eq!(removed, ServerNode::new("localhost", 12354));
+        assert_eq!(ch.len(), 9);
+
+        for (i, node) in before.iter().enumerate() {
+            let s = format!("{}", i);
+            if node != &removed {
+                assert_eq!(ch.get_str(&s), Some(node));
+            } else {
+                assert_ne!(ch.get_str(&s), Some(node));
+            }
+        }
+
+        ch.add(&removed);
+        for (i, node) in before.iter().enumerate() {
+            assert_eq!(ch.get_str(&format!("{}", i)), Some(node));
+        }
+    }
+
+    #[test]
+    fn remove_from_middle() {
+        let nodes = local_nodes(5);
+
+        let mut ch = JumpHash::with_hash(test_hash);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+
+        let before = (0..1000)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        ch.remove(&nodes[1]);
+        assert_eq!(ch.len(), nodes.len() - 1);
+        for (i, node) in before.iter().enumerate() {
+            let after = ch.get_str(&format!("{}", i)).unwrap();
+            assert_ne!(after, &nodes[1]);
+            if node != &nodes[1] && node != &nodes[4] {
+                assert_eq!(after, node);
+            }
+        }
+
+        // The last node moved into the freed bucket
+        assert_eq!(ch.pop(), Some(nodes[3].clone()));
+        ch.remove(&nodes[4]);
+        assert_eq!(ch.len(), 2);
+
+        ch.remove(&nodes[1]);
+        assert_eq!(ch.len(), 2);
+    }
+}
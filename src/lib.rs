@@ -10,13 +10,44 @@
 //! kind of hashing such that when a hash table is resized and consistent hashing is used,
 //! only K/n keys need to be remapped on average, where K is the number of keys, and n
 //! is hte number of slots.
+//!
+//! Md5 is the default hash function (the `md5` feature, enabled by default). Other hash
+//! functions are available in `hash` behind their own features: `xxhash`, `murmur3`, `fnv`,
+//! `crc32`, `siphash`, `blake3` and `sha1`.
 
 #[macro_use]
 extern crate log;
+#[cfg(feature = "md5")]
 extern crate md5;
 
-pub use crate::conhash::ConsistentHash;
-pub use node::Node;
+pub use crate::anchor::AnchorHash;
+pub use crate::bounded::BoundedLoadConsistentHash;
+pub use crate::conhash::{ConsistentHash, MembershipEvent, RingIter, Transaction, VirtualNode};
+pub use crate::crush::CrushMap;
+pub use crate::distributor::KeyDistributor;
+pub use crate::frozen::{ConsistentHashBuilder, FrozenConsistentHash};
+pub use crate::hash::RingHasher;
+pub use crate::jump::JumpHash;
+pub use crate::maglev::Maglev;
+pub use crate::multiprobe::MultiProbe;
+pub use node::{Node, VnodeKey};
+pub use rendezvous::Rendezvous;
+pub use shared::SharedConsistentHash;
 
+pub mod anchor;
+pub mod bounded;
+pub mod compat;
 pub mod conhash;
+pub mod crush;
+pub mod distributor;
+pub mod frozen;
+pub mod hash;
+pub mod jump;
+pub mod maglev;
+pub mod multiprobe;
 pub mod node;
+pub mod rendezvous;
+pub mod shared;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,335 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Maglev hashing](https://research.google/pubs/pub44824/)
+//!
+//! Nodes fill a lookup table of prime size by taking turns along their own permutation of the
+//! slots. A lookup is a single index into the table, every node owns almost the same number of
+//! slots, and only a few slots move to other nodes when the set of nodes changes.
+
+use std::collections::HashSet;
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::Md5U64;
+
+/// Default size of the lookup table
+pub const DEFAULT_TABLE_SIZE: usize = 65537;
+
+fn is_prime(n: usize) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut i = 2;
+    while i * i <= n {
+        if n % i == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Maglev Hash
+pub struct Maglev<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    nodes: Vec<N>,
+    table: Vec<usize>,
+    table_size: usize,
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Maglev<N, Md5U64> {
+    /// Construct with default hash function (Md5) and default table size
+    ///
+    /// Keys are hashed straight to `u64`, so lookups don't allocate.
+    pub fn new() -> Maglev<N, Md5U64> {
+        Maglev::with_hasher_and_table_size(Md5U64, DEFAULT_TABLE_SIZE)
+    }
+
+    /// Construct with default hash function (Md5) and customized table size.
+    /// `table_size` must be a prime number
+    pub fn with_table_size(table_size: usize) -> Maglev<N, Md5U64> {
+        Maglev::with_hasher_and_table_size(Md5U64, table_size)
+    }
+}
+
+impl<N: Node> Maglev<N> {
+    /// Construct with customized hash function and default table size
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> Maglev<N> {
+        Maglev::with_hash_and_table_size(hash_fn, DEFAULT_TABLE_SIZE)
+    }
+
+    /// Construct with customized hash function and table size.
+    /// `table_size` must be a prime number
+    pub fn with_hash_and_table_size(hash_fn: fn(&[u8]) -> Vec<u8>, table_size: usize) -> Maglev<N> {
+        Maglev::with_hasher_and_table_size(DigestU64(hash_fn), table_size)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> Maglev<N, H> {
+    /// Construct with customized `RingHasher` and default table size
+    pub fn with_hasher(hasher: H) -> Maglev<N, H> {
+        Maglev::with_hasher_and_table_size(hasher, DEFAULT_TABLE_SIZE)
+    }
+
+    /// Construct with customized `RingHasher` and table size.
+    /// `table_size` must be a prime number
+    pub fn with_hasher_and_table_size(hasher: H, table_size: usize) -> Maglev<N, H> {
+        assert!(
+            is_prime(table_size),
+            "table size must be a prime number, but got {}",
+            table_size
+        );
+
+        Maglev {
+            hasher,
+            nodes: Vec::new(),
+            table: Vec::new(),
+            table_size,
+        }
+    }
+
+    /// Add a new node and rebuild the lookup table. A node with the same name is replaced
+    pub fn add(&mut self, node: &N) {
+        let node_name = node.name();
+        debug!("Adding node {:?}", node_name);
+
+        match self.nodes.iter().position(|n| n.name() == node_name) {
+            Some(idx) => self.nodes[idx] = node.clone(),
+            None => {
+                // Keep nodes sorted, so the table doesn't depend on the order of additions
+                let idx = self
+                    .nodes
+                    .binary_search_by(|n| n.name().cmp(&node_name))
+                    .unwrap_or_else(|idx| idx);
+                self.nodes.insert(idx, node.clone());
+                self.populate();
+            }
+        }
+    }
+
+    /// Remove a node and rebuild the lookup table
+    pub fn remove(&mut self, node: &N) {
+        let node_name = node.name();
+        debug!("Removing node {:?}", node_name);
+
+        match self.nodes.iter().position(|n| n.name() == node_name) {
+            Some(idx) => {
+                self.nodes.remove(idx);
+                self.populate();
+            }
+            None => debug!("Node {:?} not exists", node_name),
+        }
+    }
+
+    fn populate(&mut self) {
+        self.table.clear();
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let size = self.table_size as u64;
+        let permutations = self
+            .nodes
+            .iter()
+            .map(|n| {
+                let h = self.hasher.hash(n.name().as_bytes());
+                let offset = h % size;
+                let skip = mix64(h) % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect::<Vec<_>>();
+
+        let mut next = vec![0u64; self.nodes.len()];
+        let mut entries = vec![None; self.table_size];
+        let mut filled = 0;
+        'fill: loop {
+            for (idx, &(offset, skip)) in permutations.iter().enumerate() {
+                let mut slot = (offset + next[idx] * skip) % size;
+                while entries[slot as usize].is_some() {
+                    next[idx] += 1;
+                    slot = (offset + next[idx] * skip) % size;
+                }
+                entries[slot as usize] = Some(idx);
+                next[idx] += 1;
+                filled += 1;
+                if filled == self.table_size {
+                    break 'fill;
+                }
+            }
+        }
+
+        self.table = entries.into_iter().flatten().collect();
+        debug!("Populated lookup table with {} nodes", self.nodes.len());
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    ///
+    /// Doesn't allocate, unless the hasher does, like a `DigestU64` hashing into a `Vec`.
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        if self.table.is_empty() {
+            debug!("The container is empty");
+            return None;
+        }
+
+        let slot = self.hasher.hash(key) % self.table_size as u64;
+        let node = &self.nodes[self.table[slot as usize]];
+        debug!(
+            "Found node {:?} for key {:?} in slot {}",
+            node.name(),
+            key,
+            slot
+        );
+        Some(node)
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key, walking the lookup table from the key's slot
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let mut result = Vec::with_capacity(n);
+        if n == 0 || self.table.is_empty() {
+            return result;
+        }
+
+        let slot = (self.hasher.hash(key) % self.table_size as u64) as usize;
+        let mut seen = HashSet::with_capacity(n);
+        let slots = self.table[slot..].iter().chain(self.table[..slot].iter());
+        for &idx in slots {
+            if seen.insert(idx) {
+                result.push(&self.nodes[idx]);
+                if result.len() == n || seen.len() == self.nodes.len() {
+                    break;
+                }
+            }
+        }
+        result
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Size of the lookup table
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for Maglev<N, Md5U64> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    #[test]
+    #[should_panic]
+    fn table_size_not_prime() {
+        Maglev::<ServerNode>::with_hash_and_table_size(test_hash, 65536);
+    }
+
+    #[test]
+    fn balanced_table() {
+        let nodes = local_nodes(7);
+
+        let mut ch = Maglev::with_hash_and_table_size(test_hash, 1009);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+        assert_eq!(ch.len(), nodes.len());
+
+        let mut counts = vec![0; nodes.len()];
+        for &idx in ch.table.iter() {
+            counts[idx] += 1;
+        }
+        let min = *counts.iter().min().unwrap();
+        let max = *counts.iter().max().unwrap();
+        assert!(max - min <= 1, "{:?}", counts);
+    }
+
+    #[test]
+    fn minimal_disruption() {
+        let nodes = local_nodes(10);
+
+        let mut ch = Maglev::with_hash(test_hash);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+
+        // Insertion order doesn't matter
+        let mut reversed = Maglev::with_hash(test_hash);
+        for node in nodes.iter().rev() {
+            reversed.add(node);
+        }
+        assert_eq!(ch.table.len(), reversed.table.len());
+        for i in 0..1000 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), reversed.get_str(&s));
+        }
+
+        const KEYS: usize = 10000;
+        let before = (0..KEYS)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        ch.remove(&nodes[3]);
+
+        let mut moved = 0;
+        for (i, node) in before.iter().enumerate() {
+            let after = ch.get_str(&format!("{}", i)).unwrap();
+            assert_ne!(after, &nodes[3]);
+            if node != &nodes[3] && node != after {
+                moved += 1;
+            }
+        }
+        assert!(moved < KEYS / 50, "{} keys moved", moved);
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn md5_u64_matches_digest() {
+        use crate::hash::default_md5_hash_fn;
+
+        let mut ch = Maglev::with_table_size(1009);
+        let mut digest = Maglev::with_hash_and_table_size(default_md5_hash_fn, 1009);
+        for node in local_nodes(7).iter() {
+            ch.add(node);
+            digest.add(node);
+        }
+        assert_eq!(ch.table, digest.table);
+        for i in 0..1000 {
+            let s = format!("{}", i);
+            assert_eq!(ch.get_str(&s), digest.get_str(&s));
+        }
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Multi-probe consistent hashing](https://arxiv.org/abs/1505.00062)
+//!
+//! Every node has a single point on the ring. A key is hashed `num_probes` times and the node
+//! closest to any of the probes (clockwise) wins, which keeps the peak-to-average load ratio
+//! low without virtual nodes.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+/// Default number of probes per key, giving a peak-to-average load ratio of about 1.05
+pub const DEFAULT_NUM_PROBES: usize = 21;
+
+/// Multi-probe Consistent Hash
+pub struct MultiProbe<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    num_probes: usize,
+    nodes: BTreeMap<u64, N>,
+    points: HashMap<String, u64>,
+}
+
+impl<N: Node> MultiProbe<N> {
+    /// Construct with default hash function (Md5) and default number of probes
+    #[cfg(feature = "md5")]
+    pub fn new() -> MultiProbe<N> {
+        MultiProbe::with_hash_and_probes(default_md5_hash_fn, DEFAULT_NUM_PROBES)
+    }
+
+    /// Construct with default hash function (Md5) and customized number of probes
+    #[cfg(feature = "md5")]
+    pub fn with_probes(num_probes: usize) -> MultiProbe<N> {
+        MultiProbe::with_hash_and_probes(default_md5_hash_fn, num_probes)
+    }
+
+    /// Construct with customized hash function and default number of probes
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> MultiProbe<N> {
+        MultiProbe::with_hash_and_probes(hash_fn, DEFAULT_NUM_PROBES)
+    }
+
+    /// Construct with customized hash function and number of probes
+    pub fn with_hash_and_probes(hash_fn: fn(&[u8]) -> Vec<u8>, num_probes: usize) -> MultiProbe<N> {
+        MultiProbe::with_hasher_and_probes(DigestU64(hash_fn), num_probes)
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> MultiProbe<N, H> {
+    /// Construct with customized `RingHasher` and default number of probes
+    pub fn with_hasher(hasher: H) -> MultiProbe<N, H> {
+        MultiProbe::with_hasher_and_probes(hasher, DEFAULT_NUM_PROBES)
+    }
+
+    /// Construct with customized `RingHasher` and number of probes
+    pub fn with_hasher_and_probes(hasher: H, num_probes: usize) -> MultiProbe<N, H> {
+        assert!(num_probes > 0, "number of probes must be at least 1");
+
+        MultiProbe {
+            hasher,
+            num_probes,
+            nodes: BTreeMap::new(),
+            points: HashMap::new(),
+        }
+    }
+
+    /// Add a new node. A node with the same name is replaced
+    pub fn add(&mut self, node: &N) {
+        let node_name = node.name();
+
+        // Remove it first
+        self.remove(node);
+
+        let point = self.hasher.hash(node_name.as_bytes());
+        debug!("Adding node {:?}, point is {}", node_name, point);
+
+        self.points.insert(node_name, point);
+        self.nodes.insert(point, node.clone());
+    }
+
+    /// Remove a node
+    pub fn remove(&mut self, node: &N) {
+        let node_name = node.name();
+        debug!("Removing node {:?}", node_name);
+
+        match self.points.remove(&node_name) {
+            Some(point) => {
+                self.nodes.remove(&point);
+            }
+            None => debug!("Node {:?} not exists", node_name),
+        }
+    }
+
+    // Clockwise successor of a probe that is not taken, and its distance from the probe
+    fn successor(&self, probe: u64, taken: &[u64]) -> Option<(u64, u64)> {
+        self.nodes
+            .range(probe..)
+            .chain(self.nodes.range(..probe))
+            .map(|(&point, _)| point)
+            .find(|point| !taken.contains(point))
+            .map(|point| (point, point.wrapping_sub(probe)))
+    }
+
+    // Point of the node closest to any of the probes of a hashed key, skipping taken points
+    fn closest_point(&self, h: u64, taken: &[u64]) -> Option<u64> {
+        // Derive the probes from one hash of the key, like double hashing
+        let (mut best_point, mut best_distance) = self.successor(h, taken)?;
+        for probe in 1..self.num_probes as u64 {
+            let (point, distance) = self.successor(mix64(h.wrapping_add(probe)), taken)?;
+            if distance < best_distance {
+                best_point = point;
+                best_distance = distance;
+            }
+        }
+        Some(best_point)
+    }
+
+    fn hash_key(&self, key: &[u8]) -> Option<u64> {
+        if self.nodes.is_empty() {
+            debug!("The container is empty");
+            return None;
+        }
+        Some(self.hasher.hash(key))
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        let point = self.closest_point(self.hash_key(key)?, &[])?;
+        let node = &self.nodes[&point];
+        debug!("Found node {:?} for key {:?}", node.name(), key);
+        Some(node)
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key, the closest node first
+    ///
+    /// Every other node is found by probing with the key re-hashed with the replica number,
+    /// skipping the nodes already chosen, so the replicas of a key are spread like the keys
+    /// themselves.
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let h = match self.hash_key(key) {
+            Some(h) => h,
+            None => return Vec::new(),
+        };
+
+        let want = n.min(self.nodes.len());
+        let mut taken = Vec::with_capacity(want);
+        for replica in 0..want as u64 {
+            let seeded = if replica == 0 {
+                h
+            } else {
+                mix64(h ^ mix64(replica))
+            };
+            match self.closest_point(seeded, &taken) {
+                Some(point) => taken.push(point),
+                None => break,
+            }
+        }
+        taken.iter().map(|point| &self.nodes[point]).collect()
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of probes per key
+    pub fn num_probes(&self) -> usize {
+        self.num_probes
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for MultiProbe<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash};
+
+    #[test]
+    fn one_probe_is_plain_ring() {
+        let mut ch = MultiProbe::with_hash_and_probes(test_hash, 1);
+        for node in local_nodes(5).iter() {
+            ch.add(node);
+        }
+
+        for i in 0..100 {
+            let s = format!("{}", i);
+            let h = ch.hasher.hash(s.as_bytes());
+            let expected = ch
+                .nodes
+                .range(h..)
+                .chain(ch.nodes.range(..h))
+                .next()
+                .map(|(_, n)| n);
+            assert_eq!(ch.get_str(&s), expected);
+        }
+    }
+
+    #[test]
+    fn balanced_load() {
+        let nodes = local_nodes(10);
+
+        let mut ch = MultiProbe::with_hash(test_hash);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+        assert_eq!(ch.len(), nodes.len());
+
+        const KEYS: usize = 20000;
+        let mut loads = HashMap::new();
+        for i in 0..KEYS {
+            let node = ch.get_str(&format!("{}", i)).unwrap();
+            *loads.entry(node.name()).or_insert(0usize) += 1;
+        }
+
+        let peak = *loads.values().max().unwrap() as f64;
+        let average = KEYS as f64 / nodes.len() as f64;
+        assert!(peak / average < 1.25, "peak-to-average {}", peak / average);
+    }
+
+    #[test]
+    fn remove_only_moves_own_keys() {
+        let nodes = local_nodes(10);
+
+        let mut ch = MultiProbe::with_hash(test_hash);
+        for node in nodes.iter() {
+            ch.add(node);
+        }
+
+        let before = (0..1000)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        ch.remove(&nodes[5]);
+        for (i, node) in before.iter().enumerate() {
+            let s = format!("{}", i);
+            if node != &nodes[5] {
+                assert_eq!(ch.get_str(&s), Some(node));
+            } else {
+                assert_ne!(ch.get_str(&s), Some(node));
+            }
+        }
+
+        let list = ch.get_str_n("hello", 20);
+        assert_eq!(list.len(), nodes.len() - 1);
+        assert_eq!(Some(list[0]), ch.get_str("hello"));
+    }
+}
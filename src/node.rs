@@ -8,4 +8,52 @@
 
 pub trait Node: Clone {
     fn name(&self) -> String;
+
+    /// Failure domain of the node, from the widest level to the narrowest,
+    /// for example `["us-east", "us-east-1a", "rack-7"]` for region, zone and rack
+    ///
+    /// Nodes without a failure domain are in a domain of their own at every level.
+    fn failure_domain(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Derives the key hashed into the position of each virtual node of a node
+///
+/// Implemented for every `Fn(&N, usize) -> Vec<u8>`, so a closure can add a seed prefix or
+/// use a stable node ID instead of `Node::name()`.
+pub trait VnodeKey<N> {
+    /// Key of the virtual node of `node` with replica index `replica`
+    fn vnode_key(&self, node: &N, replica: usize) -> Vec<u8>;
+}
+
+impl<N, F> VnodeKey<N> for F
+where
+    F: Fn(&N, usize) -> Vec<u8>,
+{
+    fn vnode_key(&self, node: &N, replica: usize) -> Vec<u8> {
+        self(node, replica)
+    }
+}
+
+/// `"{name}:{replica}"`, the default key of virtual nodes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NameReplicaKey;
+
+impl<N: Node> VnodeKey<N> for NameReplicaKey {
+    fn vnode_key(&self, node: &N, replica: usize) -> Vec<u8> {
+        format!("{}:{}", node.name(), replica).into_bytes()
+    }
+}
+
+/// The bytes of `Node::name()` followed by the replica index as a big-endian `u32`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryKey;
+
+impl<N: Node> VnodeKey<N> for BinaryKey {
+    fn vnode_key(&self, node: &N, replica: usize) -> Vec<u8> {
+        let mut key = node.name().into_bytes();
+        key.extend_from_slice(&(replica as u32).to_be_bytes());
+        key
+    }
 }
@@ -0,0 +1,205 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! [Rendezvous hashing](https://en.wikipedia.org/wiki/Rendezvous_hashing), also known as
+//! highest random weight (HRW) hashing
+//!
+//! Every node is scored against the key and the node with the highest score wins. Weighted
+//! nodes use the logarithmic method, `-weight / ln(h)` with `h` uniform in `(0, 1)`, so the
+//! share of keys of each node is proportional to its weight.
+
+use std::cmp::Ordering;
+
+use crate::{
+    hash::{mix64, DigestU64, RingHasher},
+    Node,
+};
+
+#[cfg(feature = "md5")]
+use crate::hash::default_md5_hash_fn;
+
+struct WeightedNode<N> {
+    node: N,
+    name_hash: u64,
+    weight: f64,
+}
+
+impl<N> WeightedNode<N> {
+    fn score(&self, key_hash: u64) -> f64 {
+        // Map the top 53 bits into the open interval (0, 1)
+        let h = mix64(self.name_hash ^ key_hash);
+        let u = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        -self.weight / u.ln()
+    }
+}
+
+/// Rendezvous (highest random weight) Hash
+pub struct Rendezvous<N: Node, H: RingHasher<Position = u64> = DigestU64> {
+    hasher: H,
+    nodes: Vec<WeightedNode<N>>,
+}
+
+impl<N: Node> Rendezvous<N> {
+    /// Construct with default hash function (Md5)
+    #[cfg(feature = "md5")]
+    pub fn new() -> Rendezvous<N> {
+        Rendezvous::with_hash(default_md5_hash_fn)
+    }
+
+    /// Construct with customized hash function
+    pub fn with_hash(hash_fn: fn(&[u8]) -> Vec<u8>) -> Rendezvous<N> {
+        Rendezvous::with_hasher(DigestU64(hash_fn))
+    }
+}
+
+impl<N: Node, H: RingHasher<Position = u64>> Rendezvous<N, H> {
+    /// Construct with customized `RingHasher`
+    pub fn with_hasher(hasher: H) -> Rendezvous<N, H> {
+        Rendezvous {
+            hasher,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Add a new node with `weight`. A node with the same name is replaced
+    pub fn add(&mut self, node: &N, weight: f64) {
+        assert!(
+            weight.is_finite() && weight > 0.0,
+            "weight must be a positive number, but got {}",
+            weight
+        );
+
+        let node_name = node.name();
+        debug!("Adding node {:?} with weight {}", node_name, weight);
+
+        // Remove it first
+        self.remove(node);
+
+        let name_hash = self.hasher.hash(node_name.as_bytes());
+        self.nodes.push(WeightedNode {
+            node: node.clone(),
+            name_hash,
+            weight,
+        });
+    }
+
+    /// Remove a node
+    pub fn remove(&mut self, node: &N) {
+        let node_name = node.name();
+        debug!("Removing node {:?}", node_name);
+        self.nodes.retain(|n| n.node.name() != node_name);
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a N> {
+        let key_hash = self.hasher.hash(key);
+        let found = self
+            .nodes
+            .iter()
+            .map(|n| (n.score(key_hash), n))
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+            .map(|(_, n)| &n.node);
+
+        match found {
+            Some(node) => debug!("Found node {:?} for key {:?}", node.name(), key),
+            None => debug!("The container is empty"),
+        }
+        found
+    }
+
+    /// Get a node by string key
+    pub fn get_str<'a>(&'a self, key: &str) -> Option<&'a N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get the `n` nodes with the highest scores by key, from the highest to the lowest
+    pub fn get_n<'a>(&'a self, key: &[u8], n: usize) -> Vec<&'a N> {
+        let key_hash = self.hasher.hash(key);
+        let mut scored = self
+            .nodes
+            .iter()
+            .map(|n| (n.score(key_hash), n))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        scored.into_iter().take(n).map(|(_, n)| &n.node).collect()
+    }
+
+    /// Get the `n` nodes with the highest scores by string key
+    pub fn get_str_n<'a>(&'a self, key: &str, n: usize) -> Vec<&'a N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for Rendezvous<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{local_nodes, test_hash, ServerNode};
+
+    #[test]
+    fn remove_only_moves_own_keys() {
+        let nodes = local_nodes(5);
+
+        let mut ch = Rendezvous::with_hash(test_hash);
+        for node in nodes.iter() {
+            ch.add(node, 1.0);
+        }
+        assert_eq!(ch.len(), nodes.len());
+
+        let before = (0..1000)
+            .map(|i| ch.get_str(&format!("{}", i)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        ch.remove(&nodes[2]);
+        assert_eq!(ch.len(), nodes.len() - 1);
+
+        for (i, node) in before.iter().enumerate() {
+            let s = format!("{}", i);
+            if node != &nodes[2] {
+                assert_eq!(ch.get_str(&s), Some(node));
+            } else {
+                assert_ne!(ch.get_str(&s), Some(node));
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_share() {
+        let small = ServerNode::new("localhost", 12345);
+        let large = ServerNode::new("localhost", 12346);
+
+        let mut ch = Rendezvous::with_hash(test_hash);
+        ch.add(&small, 1.0);
+        ch.add(&large, 3.0);
+
+        const KEYS: usize = 10000;
+        let large_keys = (0..KEYS)
+            .filter(|i| ch.get_str(&format!("{}", i)) == Some(&large))
+            .count();
+
+        // Expect about 75% of the keys
+        let share = large_keys as f64 / KEYS as f64;
+        assert!(share > 0.72 && share < 0.78, "share {}", share);
+    }
+}
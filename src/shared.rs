@@ -0,0 +1,327 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A `ConsistentHash` shared between threads
+//!
+//! Readers load the current immutable snapshot of the ring through an atomic pointer and never
+//! block. Writers are serialized: each `update` clones the current ring, applies a batch of
+//! changes to the clone and publishes it as the new snapshot in a single swap.
+
+use std::{
+    ops::DerefMut,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use arc_swap::ArcSwap;
+
+use crate::{
+    hash::RingHasher,
+    node::{NameReplicaKey, VnodeKey},
+    ConsistentHash, MembershipEvent, Node,
+};
+
+/// Consistent Hash with lock-free reads and batched writes
+pub struct SharedConsistentHash<
+    N: Node,
+    H: RingHasher = fn(&[u8]) -> Vec<u8>,
+    K: VnodeKey<N> = NameReplicaKey,
+> {
+    current: ArcSwap<ConsistentHash<N, H, K>>,
+    // Serializes the writers, who notify the subscribers
+    writer: Mutex<Vec<Sender<MembershipEvent<N>>>>,
+}
+
+impl<N, H, K> SharedConsistentHash<N, H, K>
+where
+    N: Node,
+    H: RingHasher + Clone,
+    K: VnodeKey<N> + Clone,
+{
+    /// Construct with `ring` as the first snapshot
+    ///
+    /// The subscribers of `ring` are moved over and notified of the published updates.
+    pub fn new(mut ring: ConsistentHash<N, H, K>) -> SharedConsistentHash<N, H, K> {
+        let subscribers = ring.take_subscribers();
+        SharedConsistentHash {
+            current: ArcSwap::from_pointee(ring),
+            writer: Mutex::new(subscribers),
+        }
+    }
+
+    /// Subscribe to changes of the nodes, see `ConsistentHash::subscribe`
+    ///
+    /// The events of an update are sent once its snapshot is published. Subscribing on a
+    /// snapshot or inside `update` has no effect, as snapshots don't keep their subscribers.
+    pub fn subscribe(&self) -> Receiver<MembershipEvent<N>> {
+        let (tx, rx) = mpsc::channel();
+        self.lock_writer().push(tx);
+        rx
+    }
+
+    fn lock_writer(&self) -> impl DerefMut<Target = Vec<Sender<MembershipEvent<N>>>> + '_ {
+        // The subscribers stay valid, and a panicking update has only changed its own copy
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The current snapshot of the ring
+    ///
+    /// A snapshot is never modified, so a series of lookups on it is consistent even when
+    /// writers publish new snapshots in the meantime.
+    pub fn snapshot(&self) -> Arc<ConsistentHash<N, H, K>> {
+        self.current.load_full()
+    }
+
+    /// Apply a batch of changes to a copy of the ring, then publish it as the new snapshot
+    ///
+    /// Updates are serialized and readers see either none or all changes of a batch. Return
+    /// the result of `f`. If `f` panics, nothing is published and no subscriber is notified, and
+    /// later updates still work.
+    pub fn update<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut ConsistentHash<N, H, K>) -> R,
+    {
+        let mut subscribers = self.lock_writer();
+
+        let mut ring = (**self.current.load()).clone();
+        ring.record();
+        let result = f(&mut ring);
+        let events = ring.take_recorded();
+        debug!("Publishing snapshot with {} virtual nodes", ring.len());
+        self.current.store(Arc::new(ring));
+
+        for event in events {
+            subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+        }
+        result
+    }
+
+    /// Get a node by key. Return `None` if no valid node inside
+    pub fn get(&self, key: &[u8]) -> Option<N> {
+        self.current.load().get(key).cloned()
+    }
+
+    /// Get a node by string key
+    pub fn get_str(&self, key: &str) -> Option<N> {
+        self.get(key.as_bytes())
+    }
+
+    /// Get a node by key with the epoch of the snapshot it was found in. Return `None` if no
+    /// valid node inside
+    pub fn get_versioned(&self, key: &[u8]) -> Option<(N, u64)> {
+        self.current
+            .load()
+            .get_versioned(key)
+            .map(|(node, epoch)| (node.clone(), epoch))
+    }
+
+    /// Get a node by string key with the epoch of the snapshot it was found in
+    pub fn get_str_versioned(&self, key: &str) -> Option<(N, u64)> {
+        self.get_versioned(key.as_bytes())
+    }
+
+    /// Get up to `n` distinct nodes by key
+    pub fn get_n(&self, key: &[u8], n: usize) -> Vec<N> {
+        self.current
+            .load()
+            .get_n(key, n)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Get up to `n` distinct nodes by string key
+    pub fn get_str_n(&self, key: &str, n: usize) -> Vec<N> {
+        self.get_n(key.as_bytes(), n)
+    }
+
+    /// Epoch of the current snapshot, see `ConsistentHash::epoch`
+    pub fn epoch(&self) -> u64 {
+        self.current.load().epoch()
+    }
+
+    /// Number of virtual nodes in the current snapshot
+    pub fn len(&self) -> usize {
+        self.current.load().len()
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(feature = "md5")]
+impl<N: Node> Default for SharedConsistentHash<N> {
+    fn default() -> Self {
+        Self::new(ConsistentHash::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{test_hash, ServerNode};
+
+    #[test]
+    fn snapshots_are_immutable() {
+        use crate::test_util::local_nodes;
+
+        let ch = SharedConsistentHash::new(ConsistentHash::with_hash(test_hash));
+        assert_eq!(ch.get_str("hello"), None);
+
+        ch.update(|ring| {
+            for node in local_nodes(5).iter() {
+                ring.add(node, 20);
+            }
+        });
+        assert_eq!(ch.len(), 100);
+
+        let before = ch.snapshot();
+        let node = ch.get_str("hello").unwrap();
+        assert_eq!(before.get_str("hello"), Some(&node));
+
+        let removed = ch.update(|ring| {
+            ring.remove(&node);
+            ring.len()
+        });
+        assert_eq!(removed, 80);
+        assert_ne!(ch.get_str("hello"), Some(node.clone()));
+        assert_eq!(ch.get_str_n("hello", 10).len(), 4);
+
+        // The old snapshot is unchanged
+        assert_eq!(before.len(), 100);
+        assert_eq!(before.get_str("hello"), Some(&node));
+
+        // Every change of the nodes is a new epoch
+        assert_eq!(before.epoch(), 5);
+        assert_eq!(ch.epoch(), 6);
+        let (found, epoch) = ch.get_str_versioned("hello").unwrap();
+        assert_eq!(Some(found), ch.get_str("hello"));
+        assert_eq!(epoch, 6);
+    }
+
+    #[test]
+    fn panicking_update() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let ch = SharedConsistentHash::new(ConsistentHash::with_hash(test_hash));
+        ch.update(|ring| ring.add(&ServerNode::new("localhost", 12345), 20));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ch.update(|ring| {
+                ring.add(&ServerNode::new("localhost", 12346), 20);
+                panic!("failed update");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(ch.len(), 20);
+        assert_eq!(ch.epoch(), 1);
+
+        ch.update(|ring| ring.add(&ServerNode::new("localhost", 12346), 20));
+        assert_eq!(ch.len(), 40);
+        assert_eq!(ch.epoch(), 2);
+    }
+
+    #[test]
+    fn events_after_publishing() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        use MembershipEvent::*;
+
+        let node0 = ServerNode::new("localhost", 12345);
+        let node1 = ServerNode::new("localhost", 12346);
+
+        let mut ring = ConsistentHash::with_hash(test_hash);
+        let first = ring.subscribe();
+        let ch = SharedConsistentHash::new(ring);
+        let second = ch.subscribe();
+
+        ch.update(|ring| {
+            ring.add(&node0, 20);
+            ring.add(&node1, 20);
+            assert_eq!(second.try_iter().count(), 0);
+        });
+        let expected = vec![
+            NodeAdded {
+                node: node0.clone(),
+                replicas: 20,
+            },
+            NodeAdded {
+                node: node1.clone(),
+                replicas: 20,
+            },
+        ];
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), expected);
+
+        // Nothing is sent for a snapshot that is never published
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ch.update(|ring| {
+                ring.remove(&node0);
+                panic!("failed update");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(second.try_iter().count(), 0);
+
+        ch.update(|ring| ring.remove(&node0));
+        assert_eq!(
+            second.try_iter().collect::<Vec<_>>(),
+            vec![NodeRemoved { node: node0 }]
+        );
+    }
+
+    #[test]
+    fn concurrent_readers_and_writers() {
+        use std::thread;
+
+        let ch = Arc::new(SharedConsistentHash::new(ConsistentHash::with_hash(
+            test_hash,
+        )));
+        ch.update(|ring| ring.add(&ServerNode::new("localhost", 12345), 20));
+
+        let writers = (0..4)
+            .map(|w| {
+                let ch = ch.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        let node = ServerNode::new("localhost", 20000 + w * 100 + i);
+                        ch.update(|ring| ring.add(&node, 4));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let readers = (0..4)
+            .map(|_| {
+                let ch = ch.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let snapshot = ch.snapshot();
+                        let s = format!("{}", i);
+                        assert_eq!(
+                            snapshot.get_str(&s).cloned(),
+                            snapshot.get_str_n(&s, 1).pop().cloned()
+                        );
+                        assert!(ch.get_str(&s).is_some());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        // No update is lost
+        assert_eq!(ch.len(), 20 + 4 * 25 * 4);
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2016 conhash-rs developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fixtures shared by the tests of the algorithms
+
+use crate::{hash::mix64, Node};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ServerNode {
+    address: String,
+}
+
+impl Node for ServerNode {
+    fn name(&self) -> String {
+        self.address.clone()
+    }
+}
+
+impl ServerNode {
+    pub(crate) fn new(host: &str, port: u16) -> ServerNode {
+        ServerNode::with_address(&format!("{}:{}", host, port))
+    }
+
+    pub(crate) fn with_address(address: &str) -> ServerNode {
+        ServerNode {
+            address: address.to_owned(),
+        }
+    }
+}
+
+/// `count` nodes on localhost, from port 12345 upwards
+pub(crate) fn local_nodes(count: u16) -> Vec<ServerNode> {
+    (12345..12345 + count)
+        .map(|port| ServerNode::new("localhost", port))
+        .collect()
+}
+
+/// Hash function of the tests that don't depend on a hash feature: FNV-1a, finalized by
+/// SplitMix64
+pub(crate) fn test_hash(data: &[u8]) -> Vec<u8> {
+    let mut h = 0xcbf29ce484222325u64;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    mix64(h).to_be_bytes().to_vec()
+}